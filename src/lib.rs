@@ -3,515 +3,4307 @@ extern crate log;
 
 pub mod machine {
     extern crate rand;
+    extern crate rand_chacha;
+    extern crate toml;
     use rand::prelude::*;
     use rand::Rng;
+    use rand_chacha::ChaCha20Rng;
+    use std::fmt;
+    use std::str::FromStr;
 
-    pub const MAX_ROTORS: usize = 5;
+    #[cfg(feature = "serde")]
+    use serde::{Deserialize, Serialize};
+
+    pub const MAX_ROTORS: usize = 8;
     pub const MAX_REFLECTORS: usize = 3;
+    pub const MAX_GREEK_ROTORS: usize = 2;
+    pub const MAX_THIN_REFLECTORS: usize = 2;
+    pub const MAX_ENTRY_WHEELS: usize = 2;
     pub const MAX_WIRES: usize = 26;
     pub const ALPHABET: [char; MAX_WIRES] = [
         'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R',
         'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
     ];
-    pub const NO_PLUGS: [(char, char); 10] = [
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
-        ('A', 'A'),
+    pub const MAX_PLUGS: usize = MAX_WIRES / 2;
+    pub const DEFAULT_PLUG_PAIRS: usize = 10;
+    pub const NO_PLUGS: Plugboard = Plugboard { wiring: identity() };
+    pub const UKW_D_PAIRS: usize = 12;
+    pub const UHR_PAIRS: usize = 10;
+    pub const UHR_POSITIONS: usize = 40;
+
+    // The German letter engraved beside each UKW-D contact. Contacts B and O (J and Y to the
+    // Germans) are wired together for good, leaving twelve pairs to plug
+    const UKW_D_LETTERS: [char; MAX_WIRES] = [
+        'A', 'J', 'Z', 'X', 'W', 'V', 'U', 'T', 'S', 'R', 'Q', 'P', 'O', 'N', 'Y', 'M', 'L', 'K',
+        'I', 'H', 'G', 'F', 'E', 'D', 'C', 'B',
+    ];
+    const UKW_D_FIXED: (usize, usize) = (1, 14);
+
+    // The Uhr's scrambler disc joins each of the 40 contacts on its a-plug face to one on its
+    // b-plug face, as in the published wiring table. Plug na has its thick pin on contact 4n and
+    // its thin one on 4n + 2, while plug nb sits on the same pair of contacts in slot
+    // UHR_B_SLOTS[n] of the other face, so that at dial 0 each na reaches nb
+    const UHR_WIRING: [usize; UHR_POSITIONS] = [
+        6, 31, 4, 29, 18, 39, 16, 25, 30, 23, 28, 1, 38, 11, 36, 37, 26, 27, 24, 21, 14, 3, 12, 17,
+        2, 7, 0, 33, 10, 35, 8, 5, 22, 19, 20, 13, 34, 15, 32, 9,
     ];
+    const UHR_B_SLOTS: [usize; UHR_PAIRS] = [1, 4, 7, 9, 6, 3, 0, 2, 5, 8];
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Error {
+        UnknownRotor(String),
+        UnknownReflector(String),
+        UnknownEntryWheel(String),
+        UnknownModel(String),
+        UnknownFitness(String),
+        NotInModel(String, String),
+        InvalidWiring(String),
+        InvalidReflector(String),
+        InvalidDefinition(String),
+        InvalidKey(String),
+        InvalidUkwD(String),
+        InvalidUhr(String),
+        InvalidLanguageModel(String),
+        InvalidLetter(char),
+        InvalidPlugPair(String),
+        SelfPlug(char),
+        DuplicatePlug(char),
+        TooManyPlugs(usize),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Error::UnknownRotor(name) => write!(f, "unknown rotor '{}'", name),
+                Error::UnknownReflector(name) => write!(f, "unknown reflector '{}'", name),
+                Error::UnknownEntryWheel(name) => write!(f, "unknown entry wheel '{}'", name),
+                Error::UnknownModel(name) => write!(f, "unknown machine model '{}'", name),
+                Error::UnknownFitness(name) => write!(
+                    f,
+                    "unknown fitness '{}', expected one of {}",
+                    name,
+                    crate::fitness::FITNESS_NAMES.join(", ")
+                ),
+                Error::NotInModel(model, reason) => write!(f, "Enigma {} {}", model, reason),
+                Error::InvalidWiring(encoding) => write!(
+                    f,
+                    "invalid wiring '{}', expected each letter A-Z exactly once",
+                    encoding
+                ),
+                Error::InvalidReflector(name) => write!(
+                    f,
+                    "reflector '{}' must pair every letter with a different letter",
+                    name
+                ),
+                Error::InvalidDefinition(reason) => {
+                    write!(f, "invalid component definition: {}", reason)
+                }
+                Error::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+                Error::InvalidUkwD(reason) => write!(f, "invalid UKW-D wiring: {}", reason),
+                Error::InvalidUhr(reason) => write!(f, "invalid Uhr setting: {}", reason),
+                Error::InvalidLanguageModel(reason) => {
+                    write!(f, "invalid language model: {}", reason)
+                }
+                Error::InvalidLetter(c) => write!(f, "invalid letter '{}', expected A-Z", c),
+                Error::InvalidPlugPair(p) => write!(f, "invalid plug pair '{}'", p),
+                Error::SelfPlug(c) => write!(f, "letter {} cannot be plugged to itself", c),
+                Error::DuplicatePlug(c) => write!(f, "letter {} is plugged more than once", c),
+                Error::TooManyPlugs(n) => {
+                    write!(f, "{} plug pairs given but at most {} fit", n, MAX_PLUGS)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
 
-    #[derive(Debug, Copy, Clone)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "RotorDef", into = "RotorDef")
+    )]
     pub struct Rotor {
-        name: &'static str,
+        name: String,
         wiring: [usize; MAX_WIRES],
         inv_wiring: [usize; MAX_WIRES],
-        turnover_post: usize,
+        notches: [bool; MAX_WIRES],
     }
 
     impl Rotor {
-        pub fn new(name: &'static str, encoding: &'static str, turnover_pos: char) -> Rotor {
-            let wiring = gen_wiring(encoding);
-            Rotor {
-                name,
+        pub fn new(name: &str, encoding: &str, notches: &str) -> Result<Rotor, Error> {
+            let wiring = gen_wiring(encoding)?;
+            let mut notch_positions = [false; MAX_WIRES];
+
+            for n in notches.chars() {
+                notch_positions[checked_wire(n)?] = true;
+            }
+
+            Ok(Rotor {
+                name: name.to_string(),
                 wiring,
                 inv_wiring: inv(wiring),
-                turnover_post: wire(turnover_pos),
-            }
+                notches: notch_positions,
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn at_notch(&self, offset: usize) -> bool {
+            self.notches[offset]
         }
 
         fn push(&self, input: usize, offset: usize) -> usize {
             let shift = (MAX_WIRES + input + offset) % MAX_WIRES;
             let output = (MAX_WIRES + self.wiring[shift] - offset) % MAX_WIRES;
             trace!("PUSH {} :: {} -> {} -> {}", self.name, input, shift, output);
-            return output;
+            output
         }
 
         fn pull(&self, input: usize, offset: usize) -> usize {
             let shift = (MAX_WIRES + input + offset) % MAX_WIRES;
             let output = (MAX_WIRES + self.inv_wiring[shift] - offset) % MAX_WIRES;
             trace!("PULL {} :: {} -> {} -> {}", self.name, input, shift, output);
-            return output;
+            output
         }
     }
 
-    #[derive(Debug, Copy, Clone)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "WiringDef", into = "WiringDef")
+    )]
     pub struct Reflector {
-        name: &'static str,
+        name: String,
         wiring: [usize; MAX_WIRES],
     }
 
     impl Reflector {
-        pub fn new(name: &'static str, encoding: &'static str) -> Reflector {
-            Reflector {
-                name,
-                wiring: gen_wiring(encoding),
+        pub fn new(name: &str, encoding: &str) -> Result<Reflector, Error> {
+            let wiring = gen_wiring(encoding)?;
+
+            if (0..MAX_WIRES).any(|i| wiring[i] == i || wiring[wiring[i]] != i) {
+                return Err(Error::InvalidReflector(name.to_string()));
             }
-        }
-    }
 
-    #[derive(Debug, Copy, Clone)]
-    pub struct State {
-        left_rotor: Rotor,
-        center_rotor: Rotor,
-        right_rotor: Rotor,
-        setting: [usize; 3],
-        offsets: [usize; 3],
-        plug_board: [usize; MAX_WIRES],
-        reflector: Reflector,
-    }
+            Ok(Reflector {
+                name: name.to_string(),
+                wiring,
+            })
+        }
 
-    impl State {
-        pub fn new(
-            rotors: (Rotor, Rotor, Rotor),
-            initial: [char; 3],
-            plugs: [(char, char); 10],
-            reflector: Reflector,
-        ) -> State {
-            let (left_rotor, center_rotor, right_rotor) = rotors;
-            let initial_settings = [wire(initial[0]), wire(initial[1]), wire(initial[2])];
+        // Plugs a UKW-D from twelve pairs in German notation such as "AF-CH-DE-..." (spaces work
+        // as well as dashes). The reflector is named "D:" followed by its pairs, each in
+        // alphabetical order and sorted, so keys and reflector_by_name can carry the wiring
+        // around and one wiring always gets the same name
+        pub fn ukw_d(pairs: &str) -> Result<Reflector, Error> {
+            let pairs: Vec<String> = pairs
+                .split(|c: char| c == '-' || c.is_whitespace())
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_ascii_uppercase())
+                .collect();
 
-            State {
-                left_rotor,
-                center_rotor,
-                right_rotor,
-                setting: initial_settings,
-                offsets: [wire(initial[0]), wire(initial[1]), wire(initial[2])],
-                plug_board: gen_board(plugs),
-                reflector,
+            if pairs.len() != UKW_D_PAIRS {
+                return Err(Error::InvalidUkwD(format!(
+                    "{} pairs given but it takes {}",
+                    pairs.len(),
+                    UKW_D_PAIRS
+                )));
             }
-        }
 
-        pub fn new_random() -> State {
-            let (left_rotor, center_rotor, right_rotor) = random_rotors();
-            let reflector = random_reflector();
-            let initial = random_settings();
-            let plugs = random_plugs();
-            let initial_settings = [wire(initial[0]), wire(initial[1]), wire(initial[2])];
+            let mut wiring = [usize::MAX; MAX_WIRES];
+            wiring[UKW_D_FIXED.0] = UKW_D_FIXED.1;
+            wiring[UKW_D_FIXED.1] = UKW_D_FIXED.0;
 
-            State {
-                left_rotor,
-                center_rotor,
-                right_rotor,
-                setting: initial_settings,
-                offsets: initial_settings,
-                plug_board: gen_board(plugs),
-                reflector,
-            }
-        }
+            for pair in &pairs {
+                let contacts = pair
+                    .chars()
+                    .map(|c| {
+                        UKW_D_LETTERS
+                            .iter()
+                            .position(|&l| l == c)
+                            .ok_or(Error::InvalidLetter(c))
+                    })
+                    .collect::<Result<Vec<usize>, Error>>()?;
 
-        pub fn show(&self) -> String {
-            format!(
-                "State(Rotors={:>3}|{:>3}|{:>3} Settings={}|{}|{} Reflector={} Plugs={:?})",
-                self.left_rotor.name,
-                self.center_rotor.name,
-                self.right_rotor.name,
-                ALPHABET[self.setting[0]],
-                ALPHABET[self.setting[1]],
-                ALPHABET[self.setting[2]],
-                self.reflector.name,
-                self.plug_board,
-            )
-        }
+                let (a, b) = match contacts[..] {
+                    [a, b] if a != b => (a, b),
+                    _ => return Err(Error::InvalidUkwD(format!("bad pair '{}'", pair))),
+                };
+                for contact in [a, b] {
+                    if contact == UKW_D_FIXED.0 || contact == UKW_D_FIXED.1 {
+                        return Err(Error::InvalidUkwD(String::from(
+                            "J and Y are wired together and cannot be plugged",
+                        )));
+                    }
+                    if wiring[contact] != usize::MAX {
+                        return Err(Error::InvalidUkwD(format!(
+                            "letter {} is used more than once",
+                            UKW_D_LETTERS[contact]
+                        )));
+                    }
+                }
 
-        fn increment(&mut self) {
-            if self.offsets[1] == self.right_rotor.turnover_post {
-                self.offsets[1] = (self.offsets[1] + 1) % MAX_WIRES;
-                self.offsets[0] = (self.offsets[0] + 1) % MAX_WIRES;
+                wiring[a] = b;
+                wiring[b] = a;
             }
 
-            if self.offsets[2] == self.center_rotor.turnover_post {
-                self.offsets[1] = (self.offsets[1] + 1) % MAX_WIRES;
-            }
+            let mut names: Vec<String> = pairs
+                .iter()
+                .map(|pair| {
+                    let mut letters: Vec<char> = pair.chars().collect();
+                    letters.sort();
+                    letters.into_iter().collect()
+                })
+                .collect();
+            names.sort();
 
-            self.offsets[2] = (self.offsets[2] + 1) % MAX_WIRES;
+            Ok(Reflector {
+                name: format!("D:{}", names.join("-")),
+                wiring,
+            })
         }
 
-        pub fn encrypt(_input: char) -> char {
-            return 'A';
+        // A UKW-D's twelve plugged pairs as contact indexes, or None for a fixed reflector
+        fn ukw_d_contacts(&self) -> Option<Vec<(usize, usize)>> {
+            if !self.is_ukw_d() {
+                return None;
+            }
+
+            Some(
+                (0..MAX_WIRES)
+                    .filter(|&a| a < self.wiring[a] && a != UKW_D_FIXED.0)
+                    .map(|a| (a, self.wiring[a]))
+                    .collect(),
+            )
         }
 
-        pub fn encode(mut self, text: &String) -> String {
-            let mut output = String::new();
+        fn from_ukw_d_contacts(contacts: &[(usize, usize)]) -> Reflector {
+            let pairs: Vec<String> = contacts
+                .iter()
+                .map(|&(a, b)| format!("{}{}", UKW_D_LETTERS[a], UKW_D_LETTERS[b]))
+                .collect();
 
-            let left = self.left_rotor;
-            let center = self.center_rotor;
-            let right = self.right_rotor;
-            let plug_board = self.plug_board;
-            let reflector = self.reflector.wiring;
+            Reflector::ukw_d(&pairs.join("-")).expect("UKW-D pairs must stay valid")
+        }
 
-            for c in text.chars().map(|c| c.to_ascii_uppercase()) {
-                if c.is_ascii() && c.is_alphabetic() {
-                    // Input
-                    let input1 = ((c as u8) - b'A') as usize;
+        pub fn is_ukw_d(&self) -> bool {
+            self.name.starts_with("D:")
+        }
 
-                    // (1) Shift Rotors
-                    self.increment();
-                    let left_offset = self.offsets[0];
-                    let center_offset = self.offsets[1];
-                    let right_offset = self.offsets[2];
+        // Every UKW-D one swap away, found by trading partners between two of the pairs
+        pub fn ukw_d_neighbours(&self) -> Vec<Reflector> {
+            let contacts = match self.ukw_d_contacts() {
+                Some(contacts) => contacts,
+                None => return Vec::new(),
+            };
 
-                    // (2) Plug Board
-                    let input2 = plug_board[input1];
-                    trace!("Plug = {} -> {}", input1, input2);
+            let mut neighbours = Vec::new();
+            for i in 0..contacts.len() {
+                for j in i + 1..contacts.len() {
+                    let ((a, b), (c, d)) = (contacts[i], contacts[j]);
 
-                    // (3) First Rotor
-                    let input3 = right.push(input2, right_offset);
+                    for swapped in [[(a, c), (b, d)], [(a, d), (b, c)]] {
+                        let mut rewired = contacts.clone();
+                        rewired[i] = swapped[0];
+                        rewired[j] = swapped[1];
+                        neighbours.push(Reflector::from_ukw_d_contacts(&rewired));
+                    }
+                }
+            }
 
-                    // (4) Second Rotor
-                    let input4 = center.push(input3, center_offset);
+            neighbours
+        }
 
-                    // (5) Third Rotor
-                    let input5 = left.push(input4, left_offset);
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
 
-                    // (6) Reflector
-                    let input6 = reflector[input5];
-                    trace!("Reflector = {} -> {}", input5, input6);
+    pub fn random_ukw_d<R: Rng + ?Sized>(rng: &mut R) -> Reflector {
+        let mut contacts: Vec<usize> = (0..MAX_WIRES)
+            .filter(|&c| c != UKW_D_FIXED.0 && c != UKW_D_FIXED.1)
+            .collect();
+        contacts.shuffle(rng);
 
-                    // (7) Third Rotor Inverse
-                    let input7 = left.pull(input6, left_offset);
+        let pairs: Vec<(usize, usize)> = contacts.chunks(2).map(|p| (p[0], p[1])).collect();
+        Reflector::from_ukw_d_contacts(&pairs)
+    }
 
-                    // (8) Second Rotor Inverse
-                    let input8 = center.pull(input7, center_offset);
+    // The entry wheel (Eintrittswalze) sits between the plugboard and the right rotor. Its
+    // encoding lists the keyboard letter wired to each contact, so the military "ABC" wheel
+    // passes letters straight through while the commercial "QWERTZ" wheel follows the keys
+    #[derive(Debug, Clone)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "WiringDef", into = "WiringDef")
+    )]
+    pub struct EntryWheel {
+        name: String,
+        wiring: [usize; MAX_WIRES],
+        inv_wiring: [usize; MAX_WIRES],
+    }
 
-                    // (9) First Rotor Inverse
-                    let input9 = right.pull(input8, right_offset);
+    impl EntryWheel {
+        pub fn new(name: &str, encoding: &str) -> Result<EntryWheel, Error> {
+            let wiring = gen_wiring(encoding)?;
 
-                    // (10) Plug Board
-                    let input10 = plug_board[input9];
-                    trace!("Plug = {} -> {}", input9, input10);
+            Ok(EntryWheel {
+                name: name.to_string(),
+                wiring,
+                inv_wiring: inv(wiring),
+            })
+        }
 
-                    // Output
-                    let cout = unwire(input10);
-                    output.push(cout);
+        pub fn name(&self) -> &str {
+            &self.name
+        }
 
-                    debug!(
-                        "ENCRYPTED {} -> {} :: {} {} {} :: {} {} {}",
-                        c,
-                        cout,
-                        unwire(left_offset % MAX_WIRES),
-                        unwire(center_offset % MAX_WIRES),
-                        unwire(right_offset % MAX_WIRES),
-                        left_offset,
-                        center_offset,
-                        right_offset
-                    );
-                } else {
-                    output.push(c);
-                    debug!(
-                        "IGNORED [{}]",
-                        if c.is_control() {
-                            String::from("CONTROL")
-                        } else {
-                            c.to_string()
-                        }
-                    );
-                }
-            }
+        pub fn is_identity(&self) -> bool {
+            self.wiring == identity()
+        }
 
-            return output;
+        fn push(&self, key: usize) -> usize {
+            self.inv_wiring[key]
         }
-    }
 
-    pub fn all_rotors() -> [Rotor; 5] {
-        [
-            Rotor::new("I", "EKMFLGDQVZNTOWYHXUSPAIBRCJ", 'R'),
-            Rotor::new("II", "AJDKSIRUXBLHWTMCQGZNPYFVOE", 'F'),
-            Rotor::new("III", "BDFHJLCPRTXVZNYEIWGAKMUSQO", 'W'),
-            Rotor::new("IV", "ESOVPZJAYQUIRHXLNFTGKDCMWB", 'K'),
-            Rotor::new("V", "VZBRGITYUPSDNHLXAWMJQOFECK", 'A'),
-        ]
+        fn pull(&self, contact: usize) -> usize {
+            self.wiring[contact]
+        }
     }
 
-    pub fn rotor_by_name(name: &str) -> Rotor {
-        for r in all_rotors() {
-            if r.name == name {
-                return r;
+    impl Default for EntryWheel {
+        fn default() -> EntryWheel {
+            EntryWheel {
+                name: String::from("ABC"),
+                wiring: identity(),
+                inv_wiring: identity(),
             }
         }
+    }
 
-        panic!("Unable to find rotor by name: {}", name);
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "String", into = "String")
+    )]
+    pub struct Plugboard {
+        wiring: [usize; MAX_WIRES],
     }
 
-    fn random_rotors() -> (Rotor, Rotor, Rotor) {
-        let mut rng = rand::thread_rng();
-        let mut nums: Vec<usize> = (0..MAX_ROTORS).collect();
-        nums.shuffle(&mut rng);
+    impl Plugboard {
+        pub fn new(pairs: &[(char, char)]) -> Result<Plugboard, Error> {
+            if pairs.len() > MAX_PLUGS {
+                return Err(Error::TooManyPlugs(pairs.len()));
+            }
 
-        let rotors = all_rotors();
-        return (rotors[nums[0]], rotors[nums[1]], rotors[nums[2]]);
-    }
+            let mut wiring = identity();
 
-    pub fn all_reflectors() -> [Reflector; 3] {
-        [
-            Reflector::new("A", "EJMZALYXVBWFCRQUONTSPIKHGD"),
-            Reflector::new("B", "YRUHQSLDPXNGOKMIEBFZCWVJAT"),
-            Reflector::new("C", "FVPJIAOYEDRZXWGCTKUQSBNMHL"),
-        ]
-    }
+            for &(p1, p2) in pairs {
+                let w1 = checked_wire(p1)?;
+                let w2 = checked_wire(p2)?;
 
-    fn random_reflector() -> Reflector {
-        let mut rng = rand::thread_rng();
-        let index = rng.gen_range(0..MAX_REFLECTORS);
+                if w1 == w2 {
+                    return Err(Error::SelfPlug(unwire(w1)));
+                }
 
-        all_reflectors()[index]
-    }
+                for w in [w1, w2] {
+                    if wiring[w] != w {
+                        return Err(Error::DuplicatePlug(unwire(w)));
+                    }
+                }
 
-    pub fn reflector_by_name(name: &str) -> Reflector {
-        for r in all_reflectors() {
-            if r.name == name {
-                return r;
+                wiring[w1] = w2;
+                wiring[w2] = w1;
             }
+
+            Ok(Plugboard { wiring })
         }
 
-        panic!("Unable to find reflector by name: {}", name);
-    }
+        pub fn pairs(&self) -> Vec<(char, char)> {
+            (0..MAX_WIRES)
+                .filter(|&i| i < self.wiring[i])
+                .map(|i| (unwire(i), unwire(self.wiring[i])))
+                .collect()
+        }
 
-    fn random_settings() -> [char; 3] {
-        let mut rng = rand::thread_rng();
+        pub fn len(&self) -> usize {
+            (0..MAX_WIRES).filter(|&i| i < self.wiring[i]).count()
+        }
 
-        [
-            ALPHABET[rng.gen_range(0..MAX_WIRES)],
-            ALPHABET[rng.gen_range(0..MAX_WIRES)],
-            ALPHABET[rng.gen_range(0..MAX_WIRES)],
-        ]
+        pub fn is_empty(&self) -> bool {
+            self.wiring == identity()
+        }
     }
 
-    fn random_plugs() -> [(char, char); 10] {
-        NO_PLUGS
+    impl Default for Plugboard {
+        fn default() -> Plugboard {
+            NO_PLUGS
+        }
     }
 
-    fn gen_board(plugs: [(char, char); 10]) -> [usize; MAX_WIRES] {
-        let mut board: [usize; MAX_WIRES] = [0; MAX_WIRES];
+    impl FromStr for Plugboard {
+        type Err = Error;
 
-        for i in 0..MAX_WIRES {
-            board[i] = i;
+        fn from_str(s: &str) -> Result<Plugboard, Error> {
+            Plugboard::new(&split_plug_pairs(s)?)
         }
+    }
 
-        for (p1, p2) in plugs {
-            let w1 = wire(p1);
-            let w2 = wire(p2);
+    impl fmt::Display for Plugboard {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let pairs: Vec<String> = self
+                .pairs()
+                .iter()
+                .map(|(p1, p2)| format!("{}{}", p1, p2))
+                .collect();
 
-            board[w1] = w2;
-            board[w2] = w1;
+            write!(f, "{}", pairs.join(" "))
         }
+    }
 
-        return board;
+    // The Enigma Uhr plugs into the plugboard in place of the ten cables. The first letter of each
+    // pair takes an a-plug and the second its b-plug, and the dial (0-39) turns the disc between
+    // them. At 0 it swaps each pair just like a cable and at 4, 8, ... it still swaps letters in
+    // pairs, only different ones; at any other dial the way in and the way back differ
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "UhrDef", into = "UhrDef")
+    )]
+    pub struct Uhr {
+        pairs: [(usize, usize); UHR_PAIRS],
+        dial: usize,
+        forward: [usize; MAX_WIRES],
+        backward: [usize; MAX_WIRES],
     }
 
-    fn gen_wiring(encoding: &str) -> [usize; MAX_WIRES] {
-        let mut wiring: [usize; MAX_WIRES] = [0; MAX_WIRES];
-        let bytes = encoding.as_bytes();
+    impl Uhr {
+        pub fn new(pairs: &[(char, char)], dial: usize) -> Result<Uhr, Error> {
+            if pairs.len() != UHR_PAIRS {
+                return Err(Error::InvalidUhr(format!(
+                    "{} plug pairs given but it takes {}",
+                    pairs.len(),
+                    UHR_PAIRS
+                )));
+            }
+            if dial >= UHR_POSITIONS {
+                return Err(Error::InvalidUhr(format!(
+                    "dial {} is past {}",
+                    dial,
+                    UHR_POSITIONS - 1
+                )));
+            }
 
-        for i in 0..MAX_WIRES {
-            wiring[i] = wire(bytes[i] as char)
-        }
+            // Plugboard::new already rejects letters plugged twice or to themselves
+            Plugboard::new(pairs)?;
 
-        return wiring;
-    }
+            let mut wires = [(0, 0); UHR_PAIRS];
+            for (wire_pair, &(a, b)) in wires.iter_mut().zip(pairs) {
+                *wire_pair = (checked_wire(a)?, checked_wire(b)?);
+            }
 
-    fn inv(wiring: [usize; MAX_WIRES]) -> [usize; MAX_WIRES] {
-        let mut iwiring: [usize; MAX_WIRES] = [0; MAX_WIRES];
+            // Contacts on the b face reached from each contact on the a face and back again
+            let mut disc = [0; UHR_POSITIONS];
+            let mut disc_back = [0; UHR_POSITIONS];
+            for contact in 0..UHR_POSITIONS {
+                let to = (UHR_WIRING[(contact + dial) % UHR_POSITIONS] + UHR_POSITIONS - dial)
+                    % UHR_POSITIONS;
+                disc[contact] = to;
+                disc_back[to] = contact;
+            }
 
-        for i in 0..MAX_WIRES {
-            iwiring[wiring[i]] = i;
-        }
+            let mut b_plugs = [0; UHR_PAIRS];
+            for (n, &slot) in UHR_B_SLOTS.iter().enumerate() {
+                b_plugs[slot] = n;
+            }
 
-        return iwiring;
-    }
+            // In on a thick pin and out on the thin pin of whichever plug the disc reaches
+            let mut forward = identity();
+            for (n, &(a, b)) in wires.iter().enumerate() {
+                forward[a] = wires[b_plugs[disc[4 * n] / 4]].1;
+                forward[b] = wires[disc_back[4 * UHR_B_SLOTS[n]] / 4].0;
+            }
 
-    fn wire(c: char) -> usize {
-        return ((c as u8) - b'A') as usize;
-    }
+            Ok(Uhr {
+                pairs: wires,
+                dial,
+                forward,
+                backward: inv(forward),
+            })
+        }
 
-    fn unwire(i: usize) -> char {
-        return (i as u8 + b'A') as char;
-    }
-}
+        // Reads the pairs as written in a key, such as "AB CD EF ..."
+        pub fn from_pairs(pairs: &str, dial: usize) -> Result<Uhr, Error> {
+            Uhr::new(&split_plug_pairs(pairs)?, dial)
+        }
 
-pub mod factory {
-    use crate::machine::*;
+        pub fn dial(&self) -> usize {
+            self.dial
+        }
 
-    pub struct StateSet {
-        pub count: usize,
-        rotors: [Rotor; MAX_ROTORS],
-        selected_rotors: (usize, usize, usize),
-        rotor_indexes: (usize, usize, usize),
-        rotor_options: (
-            [usize; StateSet::MAX_LEFT_ROTOR],
-            [usize; StateSet::MAX_CENTER_ROTOR],
-            [usize; StateSet::MAX_RIGHT_ROTOR],
-        ),
-        reflectors: [Reflector; MAX_REFLECTORS],
-        selected_reflector: usize,
-        selected_settings: (usize, usize, usize),
+        pub fn with_dial(self, dial: usize) -> Result<Uhr, Error> {
+            let pairs: Vec<(char, char)> = self
+                .pairs
+                .iter()
+                .map(|&(a, b)| (unwire(a), unwire(b)))
+                .collect();
+
+            Uhr::new(&pairs, dial)
+        }
     }
 
-    impl StateSet {
-        pub const MAX_LEFT_ROTOR: usize = MAX_ROTORS;
-        pub const MAX_CENTER_ROTOR: usize = MAX_ROTORS - 1;
-        pub const MAX_RIGHT_ROTOR: usize = MAX_ROTORS - 2;
-        pub const MAX_STATES: usize = MAX_WIRES
-            * MAX_WIRES
-            * MAX_WIRES
-            * MAX_REFLECTORS
-            * StateSet::MAX_RIGHT_ROTOR
-            * StateSet::MAX_CENTER_ROTOR
-            * StateSet::MAX_LEFT_ROTOR;
+    impl fmt::Display for Uhr {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let pairs: Vec<String> = self
+                .pairs
+                .iter()
+                .map(|&(a, b)| format!("{}{}", unwire(a), unwire(b)))
+                .collect();
 
-        pub fn new() -> StateSet {
-            StateSet {
-                count: 0,
-                rotors: all_rotors(),
-                selected_rotors: (0, 1, 2),
-                rotor_indexes: (0, 0, 0),
-                rotor_options: ([0, 1, 2, 3, 4], [1, 2, 3, 4], [2, 3, 4]),
-                reflectors: all_reflectors(),
-                selected_reflector: 0,
-                selected_settings: (0, 0, 0),
-            }
+            write!(f, "{}", pairs.join(" "))
         }
+    }
 
-        fn shift(&mut self) {
-            self.count += 1;
+    #[derive(Debug, Clone)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(Serialize, Deserialize),
+        serde(try_from = "StateDef", into = "StateDef")
+    )]
+    pub struct State {
+        left_rotor: Rotor,
+        center_rotor: Rotor,
+        right_rotor: Rotor,
+        rings: [usize; 3],
+        setting: [usize; 3],
+        offsets: [usize; 3],
+        position: usize,
+        greek_rotor: Option<Rotor>,
+        greek_ring: usize,
+        greek_setting: usize,
+        plug_board: Plugboard,
+        uhr: Option<Uhr>,
+        entry_wheel: EntryWheel,
+        reflector: Reflector,
+        reflector_setting: usize,
+    }
 
-            let (mut left_set, mut center_set, mut right_set) = self.selected_settings;
+    impl State {
+        pub fn new(
+            rotors: (Rotor, Rotor, Rotor),
+            rings: [char; 3],
+            initial: [char; 3],
+            plugs: Plugboard,
+            reflector: Reflector,
+        ) -> Result<State, Error> {
+            let (left_rotor, center_rotor, right_rotor) = rotors;
+            let initial_settings = [
+                checked_wire(initial[0])?,
+                checked_wire(initial[1])?,
+                checked_wire(initial[2])?,
+            ];
 
-            right_set += 1;
-            if right_set == MAX_WIRES {
-                center_set += 1;
-                if center_set == MAX_WIRES {
-                    left_set += 1;
-                    if left_set == MAX_WIRES {
-                        let mut reflector = self.selected_reflector;
-
-                        reflector += 1;
-                        if reflector == MAX_REFLECTORS {
-                            let (mut left_index, mut center_index, mut right_index) =
-                                self.rotor_indexes;
-                            let (left_opts, mut center_opts, mut right_opts) = self.rotor_options;
-
-                            right_index += 1;
-                            if right_index == StateSet::MAX_RIGHT_ROTOR {
-                                center_index += 1;
-
-                                if center_index == StateSet::MAX_CENTER_ROTOR {
-                                    left_index += 1;
-                                    left_index %= StateSet::MAX_LEFT_ROTOR;
-
-                                    // create new right rotor options
-                                    let mut i = 0;
-                                    for l in 0..StateSet::MAX_LEFT_ROTOR {
-                                        if l != left_index {
-                                            center_opts[i] = left_opts[l];
-                                            i += 1;
-                                        }
-                                    }
-                                }
-
-                                center_index %= StateSet::MAX_CENTER_ROTOR;
-
-                                // create new right rotor options
-                                let mut i = 0;
-                                for c in 0..StateSet::MAX_CENTER_ROTOR {
-                                    if c != center_index {
-                                        right_opts[i] = center_opts[c];
-                                        i += 1;
-                                    }
-                                }
-                            }
+            Ok(State {
+                left_rotor,
+                center_rotor,
+                right_rotor,
+                rings: [
+                    checked_wire(rings[0])?,
+                    checked_wire(rings[1])?,
+                    checked_wire(rings[2])?,
+                ],
+                setting: initial_settings,
+                offsets: initial_settings,
+                position: 0,
+                greek_rotor: None,
+                greek_ring: 0,
+                greek_setting: 0,
+                plug_board: plugs,
+                uhr: None,
+                entry_wheel: EntryWheel::default(),
+                reflector,
+                reflector_setting: 0,
+            })
+        }
 
-                            right_index %= StateSet::MAX_RIGHT_ROTOR;
+        pub fn new_m4(
+            rotors: (Rotor, Rotor, Rotor, Rotor),
+            rings: [char; 4],
+            initial: [char; 4],
+            plugs: Plugboard,
+            thin_reflector: Reflector,
+        ) -> Result<State, Error> {
+            let (greek_rotor, left_rotor, center_rotor, right_rotor) = rotors;
+            let mut state = State::new(
+                (left_rotor, center_rotor, right_rotor),
+                [rings[1], rings[2], rings[3]],
+                [initial[1], initial[2], initial[3]],
+                plugs,
+                thin_reflector,
+            )?;
 
-                            let right_rotor = right_opts[right_index]; // Use next right rotor
-                            let center_rotor = center_opts[center_index]; // Use next center rotor
-                            let left_rotor = left_opts[left_index]; // Use next center rotor
+            state.greek_rotor = Some(greek_rotor);
+            state.greek_ring = checked_wire(rings[0])?;
+            state.greek_setting = checked_wire(initial[0])?;
 
-                            self.rotor_options = (left_opts, center_opts, right_opts);
-                            self.rotor_indexes = (left_index, center_index, right_index);
-                            self.selected_rotors = (left_rotor, center_rotor, right_rotor);
-                        }
+            Ok(state)
+        }
+
+        // Parses a key such as "B III-II-I 01-01-01 AAA AB CD", reflector first and then the
+        // rotors, ring settings and starting positions from left to right, followed by any plug
+        // pairs. A fourth rotor on the left makes it an M4 key like "B-Thin Beta-II-IV-I ...",
+        // a trailing "ETW:QWERTZ" swaps in another entry wheel and "UKW:Q" turns a settable
+        // reflector to another position
+        pub fn from_key(key: &str, components: &Components) -> Result<State, Error> {
+            let KeyParts {
+                reflector,
+                rotors,
+                rings,
+                settings,
+                extras,
+            } = KeyParts::parse(key)?;
 
-                        self.selected_reflector = reflector % MAX_REFLECTORS;
+            let mut plug_pairs = Vec::new();
+            let mut entry_wheel = EntryWheel::default();
+            let mut reflector_setting = 'A';
+            let mut uhr_dial = None;
+            for token in extras {
+                match token.split_once(':') {
+                    Some(("ETW", name)) => entry_wheel = components.entry_wheel_by_name(name)?,
+                    Some(("UHR", dial)) => {
+                        uhr_dial = Some(dial.parse::<usize>().map_err(|_| {
+                            Error::InvalidUhr(format!("dial '{}' is not a number", dial))
+                        })?)
                     }
+                    Some(("UKW", setting)) if setting.chars().count() == 1 => {
+                        reflector_setting = setting.chars().next().unwrap_or('A')
+                    }
+                    Some(_) => {
+                        return Err(Error::InvalidKey(format!(
+                            "'{}' has an unknown option {}",
+                            key, token
+                        )))
+                    }
+                    None => plug_pairs.push(token),
                 }
             }
+            let pairs = plug_pairs.join(" ");
+            let (plugs, uhr) = match uhr_dial {
+                Some(dial) => (NO_PLUGS, Some(Uhr::from_pairs(&pairs, dial)?)),
+                None => (pairs.parse()?, None),
+            };
 
-            self.selected_settings = (
-                left_set % MAX_WIRES,
-                center_set % MAX_WIRES,
-                right_set % MAX_WIRES,
-            );
+            if rings.len() != rotors.len() || settings.len() != rotors.len() {
+                return Err(Error::InvalidKey(format!(
+                    "'{}' needs a ring and setting for each rotor",
+                    key
+                )));
+            }
+
+            let state = match rotors[..] {
+                [left, center, right] => State::new(
+                    (
+                        components.rotor_by_name(left)?,
+                        components.rotor_by_name(center)?,
+                        components.rotor_by_name(right)?,
+                    ),
+                    [rings[0], rings[1], rings[2]],
+                    [settings[0], settings[1], settings[2]],
+                    plugs,
+                    components.reflector_by_name(reflector)?,
+                ),
+                [greek, left, center, right] => State::new_m4(
+                    (
+                        greek_rotor_by_name(greek)?,
+                        components.rotor_by_name(left)?,
+                        components.rotor_by_name(center)?,
+                        components.rotor_by_name(right)?,
+                    ),
+                    [rings[0], rings[1], rings[2], rings[3]],
+                    [settings[0], settings[1], settings[2], settings[3]],
+                    plugs,
+                    thin_reflector_by_name(reflector)?,
+                ),
+                _ => Err(Error::InvalidKey(format!(
+                    "'{}' needs three or four rotors",
+                    key
+                ))),
+            }?;
+
+            let state = match uhr {
+                Some(uhr) => state.with_uhr(uhr),
+                None => state,
+            };
+
+            state
+                .with_entry_wheel(entry_wheel)
+                .with_reflector_setting(reflector_setting)
         }
 
-        fn pick_rotors(&self) -> (Rotor, Rotor, Rotor) {
-            let (left, center, right) = self.selected_rotors;
+        pub fn new_random(plug_pairs: usize) -> Result<State, Error> {
+            State::new_random_with(&mut rand::thread_rng(), plug_pairs)
+        }
 
-            (self.rotors[left], self.rotors[center], self.rotors[right])
+        pub fn new_seeded(seed: u64, plug_pairs: usize) -> Result<State, Error> {
+            State::new_random_with(&mut ChaCha20Rng::seed_from_u64(seed), plug_pairs)
         }
 
-        fn pick_reflector(&self) -> Reflector {
-            self.reflectors[self.selected_reflector]
+        pub fn new_random_with<R: Rng + ?Sized>(
+            rng: &mut R,
+            plug_pairs: usize,
+        ) -> Result<State, Error> {
+            let rotors = random_rotors(rng);
+            let reflector = random_reflector(rng);
+            let rings = random_settings(rng);
+            let initial = random_settings(rng);
+            let plugs = random_plugs(rng, plug_pairs)?;
+
+            State::new(rotors, rings, initial, plugs, reflector)
         }
 
-        fn pick_initial_settings(&self) -> [char; 3] {
-            let (left_set, center_set, right_set) = self.selected_settings;
+        pub fn rotors(&self) -> (&Rotor, &Rotor, &Rotor) {
+            (&self.left_rotor, &self.center_rotor, &self.right_rotor)
+        }
+
+        pub fn greek_rotor(&self) -> Option<&Rotor> {
+            self.greek_rotor.as_ref()
+        }
 
+        pub fn rings(&self) -> [char; 3] {
             [
-                ALPHABET[left_set],
-                ALPHABET[center_set],
-                ALPHABET[right_set],
+                unwire(self.rings[0]),
+                unwire(self.rings[1]),
+                unwire(self.rings[2]),
             ]
         }
 
-        fn done(&self) -> bool {
-            self.count >= StateSet::MAX_STATES
+        pub fn setting(&self) -> [char; 3] {
+            [
+                unwire(self.setting[0]),
+                unwire(self.setting[1]),
+                unwire(self.setting[2]),
+            ]
         }
-    }
 
-    impl Iterator for StateSet {
-        type Item = State;
+        pub fn reflector(&self) -> &Reflector {
+            &self.reflector
+        }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            if self.done() {
-                None
-            } else {
-                let next_state = Some(State::new(
-                    self.pick_rotors(),
-                    self.pick_initial_settings(),
-                    NO_PLUGS,
-                    self.pick_reflector(),
-                ));
+        pub fn entry_wheel(&self) -> &EntryWheel {
+            &self.entry_wheel
+        }
 
-                self.shift();
+        pub fn with_entry_wheel(mut self, entry_wheel: EntryWheel) -> State {
+            self.entry_wheel = entry_wheel;
+            self
+        }
 
-                return next_state;
-            }
+        pub fn uhr(&self) -> Option<Uhr> {
+            self.uhr
+        }
+
+        // The Uhr takes the place of any plug cables
+        pub fn with_uhr(mut self, uhr: Uhr) -> State {
+            self.plug_board = NO_PLUGS;
+            self.uhr = Some(uhr);
+            self
+        }
+
+        pub fn with_reflector(mut self, reflector: Reflector) -> State {
+            self.reflector = reflector;
+            self
+        }
+
+        pub fn reflector_setting(&self) -> char {
+            unwire(self.reflector_setting)
+        }
+
+        // Commercial machines let the reflector be turned to any position by hand, though it
+        // never steps while typing
+        pub fn with_reflector_setting(mut self, setting: char) -> Result<State, Error> {
+            self.reflector_setting = checked_wire(setting)?;
+            Ok(self)
+        }
+
+        pub fn plugboard(&self) -> Plugboard {
+            self.plug_board
+        }
+
+        // Moves the rings and starting window of the three stepping rotors and rewinds to the
+        // start. Turning a ring and the window by the same amount leaves the wiring core where
+        // it was and only moves the turnover
+        pub fn with_rings_and_settings(
+            mut self,
+            rings: [char; 3],
+            settings: [char; 3],
+        ) -> Result<State, Error> {
+            for i in 0..3 {
+                self.rings[i] = checked_wire(rings[i])?;
+                self.setting[i] = checked_wire(settings[i])?;
+            }
+
+            self.reset();
+            Ok(self)
+        }
+
+        // Plug cables in place of whatever plugboard or Uhr the machine had
+        pub fn with_plugboard(mut self, plugs: Plugboard) -> State {
+            self.plug_board = plugs;
+            self.uhr = None;
+            self
+        }
+
+        pub fn show(&self) -> String {
+            let rotors = format!(
+                "{:>3}|{:>3}|{:>3}",
+                self.left_rotor.name, self.center_rotor.name, self.right_rotor.name,
+            );
+            let rings = format!(
+                "{}|{}|{}",
+                ALPHABET[self.rings[0]], ALPHABET[self.rings[1]], ALPHABET[self.rings[2]],
+            );
+            let settings = format!(
+                "{}|{}|{}",
+                ALPHABET[self.setting[0]], ALPHABET[self.setting[1]], ALPHABET[self.setting[2]],
+            );
+
+            let (rotors, rings, settings) = match &self.greek_rotor {
+                Some(greek) => (
+                    format!("{:>5}|{}", greek.name, rotors),
+                    format!("{}|{}", ALPHABET[self.greek_ring], rings),
+                    format!("{}|{}", ALPHABET[self.greek_setting], settings),
+                ),
+                None => (rotors, rings, settings),
+            };
+
+            format!(
+                "State(Rotors={} Rings={} Settings={} Reflector={} Plugs={})",
+                rotors,
+                rings,
+                settings,
+                self.reflector.name,
+                match &self.uhr {
+                    Some(uhr) => format!("{} Uhr={:02}", uhr, uhr.dial),
+                    None => self.plug_board.to_string(),
+                },
+            )
+        }
+
+        pub fn cycle_length(&self) -> usize {
+            let (_, period) = self.cycle();
+            period
+        }
+
+        // Steps taken from the initial setting before the stepping sequence repeats and the
+        // length of the repeating part.
+        fn cycle(&self) -> (usize, usize) {
+            let mut seen = vec![usize::MAX; MAX_WIRES * MAX_WIRES * MAX_WIRES];
+            let mut offsets = self.setting;
+            let mut step = 0;
+
+            loop {
+                let index = (offsets[0] * MAX_WIRES + offsets[1]) * MAX_WIRES + offsets[2];
+                if seen[index] != usize::MAX {
+                    return (seen[index], step - seen[index]);
+                }
+
+                seen[index] = step;
+                offsets = self.next_offsets(offsets);
+                step += 1;
+            }
+        }
+
+        pub fn position(&self) -> usize {
+            self.position
+        }
+
+        pub fn seek(&mut self, position: usize) {
+            self.offsets = self.offsets_at(position);
+            self.position = position;
+        }
+
+        pub fn rewind(&mut self, count: usize) {
+            self.seek(self.position.saturating_sub(count));
+        }
+
+        pub fn window_at(&self, position: usize) -> [char; 3] {
+            let offsets = self.offsets_at(position);
+
+            [unwire(offsets[0]), unwire(offsets[1]), unwire(offsets[2])]
+        }
+
+        fn offsets_at(&self, position: usize) -> [usize; 3] {
+            let limit = MAX_WIRES * MAX_WIRES * MAX_WIRES;
+
+            if position <= limit {
+                return self.advance(self.setting, position);
+            }
+
+            let (tail, period) = self.cycle();
+            self.advance(self.setting, tail + (position - tail) % period)
+        }
+
+        fn advance(&self, offsets: [usize; 3], steps: usize) -> [usize; 3] {
+            let mut offsets = offsets;
+            let mut remaining = steps;
+
+            while remaining > 0 {
+                // Until a pawl meets a notch only the right rotor moves, so skip that run at once
+                if !self.center_rotor.at_notch(offsets[1]) {
+                    let run = (0..MAX_WIRES)
+                        .find(|d| self.right_rotor.at_notch((offsets[2] + d) % MAX_WIRES))
+                        .unwrap_or(remaining)
+                        .min(remaining);
+
+                    if run > 0 {
+                        offsets[2] = (offsets[2] + run) % MAX_WIRES;
+                        remaining -= run;
+                        continue;
+                    }
+                }
+
+                offsets = self.next_offsets(offsets);
+                remaining -= 1;
+            }
+
+            offsets
+        }
+
+        fn increment(&mut self) {
+            self.offsets = self.next_offsets(self.offsets);
+            self.position += 1;
+        }
+
+        fn next_offsets(&self, offsets: [usize; 3]) -> [usize; 3] {
+            let [mut left, mut center, mut right] = offsets;
+
+            // Each pawl steps its own rotor and, when it drops into the notch of the rotor to
+            // its right, that rotor too. The center rotor's notch therefore moves the center
+            // rotor a second time as the left rotor steps (the double step).
+            if self.center_rotor.at_notch(center) {
+                center = (center + 1) % MAX_WIRES;
+                left = (left + 1) % MAX_WIRES;
+            } else if self.right_rotor.at_notch(right) {
+                center = (center + 1) % MAX_WIRES;
+            }
+
+            right = (right + 1) % MAX_WIRES;
+
+            [left, center, right]
+        }
+
+        pub fn window(&self) -> [char; 3] {
+            [
+                unwire(self.offsets[0]),
+                unwire(self.offsets[1]),
+                unwire(self.offsets[2]),
+            ]
+        }
+
+        pub fn greek_window(&self) -> Option<char> {
+            self.greek_rotor
+                .as_ref()
+                .map(|_| unwire(self.greek_setting))
+        }
+
+        pub fn reset(&mut self) {
+            self.offsets = self.setting;
+            self.position = 0;
+        }
+
+        pub fn encrypt(&mut self, input: char) -> char {
+            let c = input.to_ascii_uppercase();
+
+            if !c.is_ascii_alphabetic() {
+                debug!(
+                    "IGNORED [{}]",
+                    if c.is_control() {
+                        String::from("CONTROL")
+                    } else {
+                        c.to_string()
+                    }
+                );
+
+                return c;
+            }
+
+            // Input
+            let input1 = wire(c);
+
+            // (1) Shift Rotors
+            self.increment();
+            let left_offset = self.offsets[0];
+            let center_offset = self.offsets[1];
+            let right_offset = self.offsets[2];
+
+            // Ring settings turn the wiring core against the alphabet ring
+            let left_shift = (MAX_WIRES + left_offset - self.rings[0]) % MAX_WIRES;
+            let center_shift = (MAX_WIRES + center_offset - self.rings[1]) % MAX_WIRES;
+            let right_shift = (MAX_WIRES + right_offset - self.rings[2]) % MAX_WIRES;
+            let greek_shift = (MAX_WIRES + self.greek_setting - self.greek_ring) % MAX_WIRES;
+
+            // (2) Plug Board (or the Uhr) and Entry Wheel
+            let input2 = match &self.uhr {
+                Some(uhr) => uhr.forward[input1],
+                None => self.plug_board.wiring[input1],
+            };
+            trace!("Plug = {} -> {}", input1, input2);
+            let input2 = self.entry_wheel.push(input2);
+
+            // (3) First Rotor
+            let input3 = self.right_rotor.push(input2, right_shift);
+
+            // (4) Second Rotor
+            let input4 = self.center_rotor.push(input3, center_shift);
+
+            // (5) Third Rotor (and the non-stepping Greek rotor of an M4)
+            let input5 = self.left_rotor.push(input4, left_shift);
+            let input5 = match &self.greek_rotor {
+                Some(g) => g.push(input5, greek_shift),
+                None => input5,
+            };
+
+            // (6) Reflector
+            let reflector_shift = self.reflector_setting;
+            let input6 = (MAX_WIRES
+                + self.reflector.wiring[(input5 + reflector_shift) % MAX_WIRES]
+                - reflector_shift)
+                % MAX_WIRES;
+            trace!("Reflector = {} -> {}", input5, input6);
+
+            // (7) Third Rotor Inverse (after the Greek rotor of an M4)
+            let input6 = match &self.greek_rotor {
+                Some(g) => g.pull(input6, greek_shift),
+                None => input6,
+            };
+            let input7 = self.left_rotor.pull(input6, left_shift);
+
+            // (8) Second Rotor Inverse
+            let input8 = self.center_rotor.pull(input7, center_shift);
+
+            // (9) First Rotor Inverse
+            let input9 = self.right_rotor.pull(input8, right_shift);
+
+            // (10) Entry Wheel and Plug Board (or the Uhr, which comes back another way)
+            let input9 = self.entry_wheel.pull(input9);
+            let input10 = match &self.uhr {
+                Some(uhr) => uhr.backward[input9],
+                None => self.plug_board.wiring[input9],
+            };
+            trace!("Plug = {} -> {}", input9, input10);
+
+            // Output
+            let cout = unwire(input10);
+
+            debug!(
+                "ENCRYPTED {} -> {} :: {} {} {} :: {} {} {}",
+                c,
+                cout,
+                unwire(left_offset),
+                unwire(center_offset),
+                unwire(right_offset),
+                left_offset,
+                center_offset,
+                right_offset
+            );
+
+            cout
+        }
+
+        pub fn encode(&mut self, text: &str) -> String {
+            text.chars().map(|c| self.encrypt(c)).collect()
+        }
+    }
+
+    impl FromStr for State {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<State, Error> {
+            State::from_key(s, &Components::builtin())
+        }
+    }
+
+    // Writes the starting key in the same form from_key reads, regardless of how far the
+    // machine has stepped since
+    impl fmt::Display for State {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let mut rotors = vec![
+                self.left_rotor.name(),
+                self.center_rotor.name(),
+                self.right_rotor.name(),
+            ];
+            let mut rings: Vec<String> =
+                self.rings.iter().map(|r| format!("{:02}", r + 1)).collect();
+            let mut settings: String = self.setting.iter().map(|s| ALPHABET[*s]).collect();
+
+            if let Some(greek) = &self.greek_rotor {
+                rotors.insert(0, greek.name());
+                rings.insert(0, format!("{:02}", self.greek_ring + 1));
+                settings.insert(0, ALPHABET[self.greek_setting]);
+            }
+
+            write!(
+                f,
+                "{} {} {} {}",
+                self.reflector.name,
+                rotors.join("-"),
+                rings.join("-"),
+                settings
+            )?;
+
+            if !self.plug_board.is_empty() {
+                write!(f, " {}", self.plug_board)?;
+            }
+
+            if let Some(uhr) = &self.uhr {
+                write!(f, " {} UHR:{:02}", uhr, uhr.dial)?;
+            }
+
+            if !self.entry_wheel.is_identity() {
+                write!(f, " ETW:{}", self.entry_wheel.name)?;
+            }
+
+            if self.reflector_setting != 0 {
+                write!(f, " UKW:{}", unwire(self.reflector_setting))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    // The Abwehr Enigma G drives its rotors through gears like a counter, so a rotor turns only
+    // when the one to its right turns while at a notch and there is no double step. The
+    // reflector sits on the end of that chain and moves too, so the window shows four letters
+    #[derive(Debug, Clone)]
+    pub struct EnigmaG {
+        left_rotor: Rotor,
+        center_rotor: Rotor,
+        right_rotor: Rotor,
+        rings: [usize; 3],
+        setting: [usize; 4],
+        offsets: [usize; 4],
+        position: usize,
+        entry_wheel: EntryWheel,
+        reflector: Reflector,
+    }
+
+    impl EnigmaG {
+        pub fn new(
+            rotors: (Rotor, Rotor, Rotor),
+            rings: [char; 3],
+            initial: [char; 4],
+            reflector: Reflector,
+        ) -> Result<EnigmaG, Error> {
+            let (left_rotor, center_rotor, right_rotor) = rotors;
+            let initial_settings = [
+                checked_wire(initial[0])?,
+                checked_wire(initial[1])?,
+                checked_wire(initial[2])?,
+                checked_wire(initial[3])?,
+            ];
+
+            Ok(EnigmaG {
+                left_rotor,
+                center_rotor,
+                right_rotor,
+                rings: [
+                    checked_wire(rings[0])?,
+                    checked_wire(rings[1])?,
+                    checked_wire(rings[2])?,
+                ],
+                setting: initial_settings,
+                offsets: initial_settings,
+                position: 0,
+                entry_wheel: all_entry_wheels()[1].clone(),
+                reflector,
+            })
+        }
+
+        // Parses a key such as "UKW I-II-III 01-01-01 QAAA", where the settings give the
+        // reflector's starting position ahead of the rotors', optionally followed by an
+        // "ETW:name" entry wheel in place of the usual QWERTZ one
+        pub fn from_key(key: &str, components: &Components) -> Result<EnigmaG, Error> {
+            let KeyParts {
+                reflector,
+                rotors,
+                rings,
+                settings,
+                extras,
+            } = KeyParts::parse(key)?;
+
+            let mut entry_wheel = None;
+            for token in extras {
+                match token.split_once(':') {
+                    Some(("ETW", name)) => {
+                        entry_wheel = Some(components.entry_wheel_by_name(name)?)
+                    }
+                    _ => {
+                        return Err(Error::InvalidKey(format!(
+                            "'{}' has an unknown option {}",
+                            key, token
+                        )))
+                    }
+                }
+            }
+
+            let (left, center, right) = match rotors[..] {
+                [left, center, right] => (left, center, right),
+                _ => return Err(Error::InvalidKey(format!("'{}' needs three rotors", key))),
+            };
+            if rings.len() != 3 || settings.len() != 4 {
+                return Err(Error::InvalidKey(format!(
+                    "'{}' needs a ring for each rotor and a setting for the reflector and each rotor",
+                    key
+                )));
+            }
+
+            let machine = EnigmaG::new(
+                (
+                    components.rotor_by_name(left)?,
+                    components.rotor_by_name(center)?,
+                    components.rotor_by_name(right)?,
+                ),
+                [rings[0], rings[1], rings[2]],
+                [settings[0], settings[1], settings[2], settings[3]],
+                components.reflector_by_name(reflector)?,
+            )?;
+
+            Ok(match entry_wheel {
+                Some(entry_wheel) => machine.with_entry_wheel(entry_wheel),
+                None => machine,
+            })
+        }
+
+        pub fn rotors(&self) -> (&Rotor, &Rotor, &Rotor) {
+            (&self.left_rotor, &self.center_rotor, &self.right_rotor)
+        }
+
+        pub fn reflector(&self) -> &Reflector {
+            &self.reflector
+        }
+
+        pub fn entry_wheel(&self) -> &EntryWheel {
+            &self.entry_wheel
+        }
+
+        pub fn with_entry_wheel(mut self, entry_wheel: EntryWheel) -> EnigmaG {
+            self.entry_wheel = entry_wheel;
+            self
+        }
+
+        pub fn window(&self) -> [char; 4] {
+            [
+                unwire(self.offsets[0]),
+                unwire(self.offsets[1]),
+                unwire(self.offsets[2]),
+                unwire(self.offsets[3]),
+            ]
+        }
+
+        pub fn position(&self) -> usize {
+            self.position
+        }
+
+        pub fn reset(&mut self) {
+            self.offsets = self.setting;
+            self.position = 0;
+        }
+
+        fn increment(&mut self) {
+            self.offsets = self.next_offsets(self.offsets);
+            self.position += 1;
+        }
+
+        fn next_offsets(&self, offsets: [usize; 4]) -> [usize; 4] {
+            let [mut reflector, mut left, mut center, mut right] = offsets;
+
+            // Each notch only passes the movement on, so the carry stops at the first wheel
+            // that turns without sitting on a notch
+            if self.right_rotor.at_notch(right) {
+                if self.center_rotor.at_notch(center) {
+                    if self.left_rotor.at_notch(left) {
+                        reflector = (reflector + 1) % MAX_WIRES;
+                    }
+                    left = (left + 1) % MAX_WIRES;
+                }
+                center = (center + 1) % MAX_WIRES;
+            }
+            right = (right + 1) % MAX_WIRES;
+
+            [reflector, left, center, right]
+        }
+
+        pub fn encrypt(&mut self, input: char) -> char {
+            let c = input.to_ascii_uppercase();
+
+            if !c.is_ascii_alphabetic() {
+                return c;
+            }
+
+            self.increment();
+            let [reflector_shift, left_offset, center_offset, right_offset] = self.offsets;
+            let left_shift = (MAX_WIRES + left_offset - self.rings[0]) % MAX_WIRES;
+            let center_shift = (MAX_WIRES + center_offset - self.rings[1]) % MAX_WIRES;
+            let right_shift = (MAX_WIRES + right_offset - self.rings[2]) % MAX_WIRES;
+
+            let input = self.entry_wheel.push(wire(c));
+            let input = self.right_rotor.push(input, right_shift);
+            let input = self.center_rotor.push(input, center_shift);
+            let input = self.left_rotor.push(input, left_shift);
+
+            let input = (MAX_WIRES + self.reflector.wiring[(input + reflector_shift) % MAX_WIRES]
+                - reflector_shift)
+                % MAX_WIRES;
+
+            let input = self.left_rotor.pull(input, left_shift);
+            let input = self.center_rotor.pull(input, center_shift);
+            let input = self.right_rotor.pull(input, right_shift);
+            let cout = unwire(self.entry_wheel.pull(input));
+
+            debug!(
+                "ENCRYPTED {} -> {} :: {}",
+                c,
+                cout,
+                self.window().iter().collect::<String>()
+            );
+
+            cout
+        }
+
+        pub fn encode(&mut self, text: &str) -> String {
+            text.chars().map(|c| self.encrypt(c)).collect()
+        }
+    }
+
+    impl fmt::Display for EnigmaG {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let rings: Vec<String> = self.rings.iter().map(|r| format!("{:02}", r + 1)).collect();
+            let settings: String = self.setting.iter().map(|s| ALPHABET[*s]).collect();
+
+            write!(
+                f,
+                "{} {}-{}-{} {} {}",
+                self.reflector.name,
+                self.left_rotor.name,
+                self.center_rotor.name,
+                self.right_rotor.name,
+                rings.join("-"),
+                settings
+            )?;
+
+            if self.entry_wheel.name != all_entry_wheels()[1].name {
+                write!(f, " ETW:{}", self.entry_wheel.name)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    pub fn all_rotors() -> [Rotor; MAX_ROTORS] {
+        [
+            builtin_rotor("I", "EKMFLGDQVZNTOWYHXUSPAIBRCJ", "Q"),
+            builtin_rotor("II", "AJDKSIRUXBLHWTMCQGZNPYFVOE", "E"),
+            builtin_rotor("III", "BDFHJLCPRTXVZNYEIWGAKMUSQO", "V"),
+            builtin_rotor("IV", "ESOVPZJAYQUIRHXLNFTGKDCMWB", "J"),
+            builtin_rotor("V", "VZBRGITYUPSDNHLXAWMJQOFECK", "Z"),
+            builtin_rotor("VI", "JPGVOUMFYQBENHZRDKASXLICTW", "ZM"),
+            builtin_rotor("VII", "NZJHGRCXMYSWBOUFAIVLPEKQDT", "ZM"),
+            builtin_rotor("VIII", "FKQHTLXOCBJSPDZRAMEWNIUYGV", "ZM"),
+        ]
+    }
+
+    pub fn rotor_by_name(name: &str) -> Result<Rotor, Error> {
+        all_rotors()
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| Error::UnknownRotor(name.to_string()))
+    }
+
+    fn random_rotors<R: Rng + ?Sized>(rng: &mut R) -> (Rotor, Rotor, Rotor) {
+        let mut nums: Vec<usize> = (0..MAX_ROTORS).collect();
+        nums.shuffle(rng);
+
+        let rotors = all_rotors();
+        (
+            rotors[nums[0]].clone(),
+            rotors[nums[1]].clone(),
+            rotors[nums[2]].clone(),
+        )
+    }
+
+    pub fn all_reflectors() -> [Reflector; MAX_REFLECTORS] {
+        [
+            builtin_reflector("A", "EJMZALYXVBWFCRQUONTSPIKHGD"),
+            builtin_reflector("B", "YRUHQSLDPXNGOKMIEBFZCWVJAT"),
+            builtin_reflector("C", "FVPJIAOYEDRZXWGCTKUQSBNMHL"),
+        ]
+    }
+
+    pub fn all_greek_rotors() -> [Rotor; MAX_GREEK_ROTORS] {
+        [
+            builtin_rotor("Beta", "LEYJVCNIXWPBQMDRTAKZGFUHOS", ""),
+            builtin_rotor("Gamma", "FSOKANUERHMBTIVCXYPZJDGLWQ", ""),
+        ]
+    }
+
+    pub fn greek_rotor_by_name(name: &str) -> Result<Rotor, Error> {
+        all_greek_rotors()
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| Error::UnknownRotor(name.to_string()))
+    }
+
+    pub fn all_thin_reflectors() -> [Reflector; MAX_THIN_REFLECTORS] {
+        [
+            builtin_reflector("B-Thin", "ENKQAUYWJICOPBLMDXZVFTHRGS"),
+            builtin_reflector("C-Thin", "RDOBJNTKVEHMLFCWZAXGYIPSUQ"),
+        ]
+    }
+
+    pub fn thin_reflector_by_name(name: &str) -> Result<Reflector, Error> {
+        all_thin_reflectors()
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| Error::UnknownReflector(name.to_string()))
+    }
+
+    pub fn all_entry_wheels() -> [EntryWheel; MAX_ENTRY_WHEELS] {
+        [
+            EntryWheel::default(),
+            builtin_entry_wheel("QWERTZ", "QWERTZUIOASDFGHJKPYXCVBNML"),
+        ]
+    }
+
+    pub fn entry_wheel_by_name(name: &str) -> Result<EntryWheel, Error> {
+        all_entry_wheels()
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| Error::UnknownEntryWheel(name.to_string()))
+    }
+
+    fn random_reflector<R: Rng + ?Sized>(rng: &mut R) -> Reflector {
+        let index = rng.gen_range(0..MAX_REFLECTORS);
+
+        all_reflectors()[index].clone()
+    }
+
+    pub fn reflector_by_name(name: &str) -> Result<Reflector, Error> {
+        if let Some(pairs) = name.strip_prefix("D:") {
+            return Reflector::ukw_d(pairs);
+        }
+
+        all_reflectors()
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| Error::UnknownReflector(name.to_string()))
+    }
+
+    // A set of rotors, reflectors and entry wheels to pick from, either the historical ones or
+    // custom wirings loaded from a TOML definition like:
+    //
+    //   [[rotor]]
+    //   name = "X1"
+    //   wiring = "QWERTYUIOPASDFGHJKLZXCVBNM"
+    //   notches = "Q"
+    //
+    //   [[reflector]]
+    //   name = "R1"
+    //   wiring = "YRUHQSLDPXNGOKMIEBFZCWVJAT"
+    //
+    //   [[entry_wheel]]
+    //   name = "E1"
+    //   wiring = "QWERTZUIOASDFGHJKPYXCVBNML"
+    #[derive(Debug, Clone)]
+    pub struct Components {
+        rotors: Vec<Rotor>,
+        reflectors: Vec<Reflector>,
+        entry_wheels: Vec<EntryWheel>,
+    }
+
+    impl Components {
+        pub fn new(
+            rotors: Vec<Rotor>,
+            reflectors: Vec<Reflector>,
+            entry_wheels: Vec<EntryWheel>,
+        ) -> Components {
+            Components {
+                rotors,
+                reflectors,
+                entry_wheels,
+            }
+        }
+
+        pub fn builtin() -> Components {
+            Components {
+                rotors: all_rotors().to_vec(),
+                reflectors: all_reflectors().to_vec(),
+                entry_wheels: all_entry_wheels().to_vec(),
+            }
+        }
+
+        // Sections left out of the definition fall back to the historical components
+        pub fn from_toml(text: &str) -> Result<Components, Error> {
+            let value: toml::Value = text
+                .parse()
+                .map_err(|e: toml::de::Error| Error::InvalidDefinition(e.to_string()))?;
+
+            let mut rotors = Vec::new();
+            for table in definition_tables(&value, "rotor")? {
+                let notches = match table.get("notches") {
+                    Some(_) => definition_field(table, "rotor", "notches")?,
+                    None => "",
+                };
+
+                rotors.push(Rotor::new(
+                    definition_name(table, "rotor")?,
+                    definition_field(table, "rotor", "wiring")?,
+                    notches,
+                )?);
+            }
+
+            let mut reflectors = Vec::new();
+            for table in definition_tables(&value, "reflector")? {
+                reflectors.push(Reflector::new(
+                    definition_name(table, "reflector")?,
+                    definition_field(table, "reflector", "wiring")?,
+                )?);
+            }
+
+            let mut entry_wheels = Vec::new();
+            for table in definition_tables(&value, "entry_wheel")? {
+                entry_wheels.push(EntryWheel::new(
+                    definition_name(table, "entry_wheel")?,
+                    definition_field(table, "entry_wheel", "wiring")?,
+                )?);
+            }
+
+            for (i, rotor) in rotors.iter().enumerate() {
+                if rotors[..i].iter().any(|r| r.name == rotor.name) {
+                    return Err(Error::InvalidDefinition(format!(
+                        "rotor '{}' is defined more than once",
+                        rotor.name
+                    )));
+                }
+            }
+            for (i, reflector) in reflectors.iter().enumerate() {
+                if reflectors[..i].iter().any(|r| r.name == reflector.name) {
+                    return Err(Error::InvalidDefinition(format!(
+                        "reflector '{}' is defined more than once",
+                        reflector.name
+                    )));
+                }
+            }
+            for (i, entry_wheel) in entry_wheels.iter().enumerate() {
+                if entry_wheels[..i].iter().any(|e| e.name == entry_wheel.name) {
+                    return Err(Error::InvalidDefinition(format!(
+                        "entry wheel '{}' is defined more than once",
+                        entry_wheel.name
+                    )));
+                }
+            }
+
+            let builtin = Components::builtin();
+
+            Ok(Components {
+                rotors: if rotors.is_empty() {
+                    builtin.rotors
+                } else {
+                    rotors
+                },
+                reflectors: if reflectors.is_empty() {
+                    builtin.reflectors
+                } else {
+                    reflectors
+                },
+                entry_wheels: if entry_wheels.is_empty() {
+                    builtin.entry_wheels
+                } else {
+                    entry_wheels
+                },
+            })
+        }
+
+        pub fn rotors(&self) -> &[Rotor] {
+            &self.rotors
+        }
+
+        pub fn reflectors(&self) -> &[Reflector] {
+            &self.reflectors
+        }
+
+        pub fn entry_wheels(&self) -> &[EntryWheel] {
+            &self.entry_wheels
+        }
+
+        pub fn rotor_by_name(&self, name: &str) -> Result<Rotor, Error> {
+            self.rotors
+                .iter()
+                .find(|r| r.name == name)
+                .cloned()
+                .ok_or_else(|| Error::UnknownRotor(name.to_string()))
+        }
+
+        // A UKW-D can be plugged into any machine, so "D:" names are always accepted
+        pub fn reflector_by_name(&self, name: &str) -> Result<Reflector, Error> {
+            if let Some(pairs) = name.strip_prefix("D:") {
+                return Reflector::ukw_d(pairs);
+            }
+
+            self.reflectors
+                .iter()
+                .find(|r| r.name == name)
+                .cloned()
+                .ok_or_else(|| Error::UnknownReflector(name.to_string()))
+        }
+
+        pub fn entry_wheel_by_name(&self, name: &str) -> Result<EntryWheel, Error> {
+            self.entry_wheels
+                .iter()
+                .find(|e| e.name == name)
+                .cloned()
+                .ok_or_else(|| Error::UnknownEntryWheel(name.to_string()))
+        }
+    }
+
+    impl Default for Components {
+        fn default() -> Components {
+            Components::builtin()
+        }
+    }
+
+    // A historical machine model with the components it shipped with and the rules for how
+    // they can be set up
+    #[derive(Debug, Clone)]
+    pub struct Model {
+        name: &'static str,
+        components: Components,
+        entry_wheel: EntryWheel,
+        plugboard: bool,
+        settable_reflector: bool,
+    }
+
+    impl Model {
+        pub fn name(&self) -> &str {
+            self.name
+        }
+
+        pub fn components(&self) -> &Components {
+            &self.components
+        }
+
+        pub fn entry_wheel(&self) -> &EntryWheel {
+            &self.entry_wheel
+        }
+
+        pub fn has_plugboard(&self) -> bool {
+            self.plugboard
+        }
+
+        pub fn has_settable_reflector(&self) -> bool {
+            self.settable_reflector
+        }
+
+        pub fn state_from_key(&self, key: &str) -> Result<State, Error> {
+            self.configure(State::from_key(key, &self.components)?)
+        }
+
+        // Checks a machine only uses parts this model allows and fits the model's entry wheel.
+        // Parts are matched by wiring, since models reuse names like "I" for different rotors
+        pub fn configure(&self, state: State) -> Result<State, Error> {
+            let not_allowed =
+                |reason: String| Err(Error::NotInModel(self.name.to_string(), reason));
+            let (left, center, right) = state.rotors();
+
+            if state.greek_rotor().is_some() {
+                return not_allowed(String::from("takes three rotors"));
+            }
+            for rotor in [left, center, right] {
+                if !self.components.rotors.contains(rotor) {
+                    return not_allowed(format!("has no rotor {}", rotor.name()));
+                }
+            }
+            if self
+                .components
+                .reflector_by_name(state.reflector().name())
+                .ok()
+                .as_ref()
+                != Some(state.reflector())
+            {
+                return not_allowed(format!("has no reflector {}", state.reflector().name()));
+            }
+            if !state.entry_wheel().is_identity()
+                && state.entry_wheel().wiring != self.entry_wheel.wiring
+            {
+                return not_allowed(format!("has no entry wheel {}", state.entry_wheel().name()));
+            }
+            if !self.plugboard && (!state.plugboard().is_empty() || state.uhr().is_some()) {
+                return not_allowed(String::from("has no plugboard"));
+            }
+            if !self.settable_reflector && state.reflector_setting() != 'A' {
+                return not_allowed(String::from("can not turn its reflector"));
+            }
+
+            Ok(state.with_entry_wheel(self.entry_wheel.clone()))
+        }
+
+        pub fn seeded_state(&self, seed: u64, plug_pairs: usize) -> Result<State, Error> {
+            self.random_state(&mut ChaCha20Rng::seed_from_u64(seed), plug_pairs)
+        }
+
+        pub fn random_state<R: Rng + ?Sized>(
+            &self,
+            rng: &mut R,
+            plug_pairs: usize,
+        ) -> Result<State, Error> {
+            let rotors: Vec<Rotor> = self
+                .components
+                .rotors
+                .choose_multiple(rng, 3)
+                .cloned()
+                .collect();
+            let reflector = self
+                .components
+                .reflectors
+                .choose(rng)
+                .cloned()
+                .ok_or_else(|| {
+                    Error::NotInModel(self.name.to_string(), String::from("has no reflector"))
+                })?;
+            let rings = random_settings(rng);
+            let initial = random_settings(rng);
+            let plugs = if self.plugboard {
+                random_plugs(rng, plug_pairs)?
+            } else {
+                NO_PLUGS
+            };
+            let reflector_setting = if self.settable_reflector {
+                ALPHABET[rng.gen_range(0..MAX_WIRES)]
+            } else {
+                'A'
+            };
+
+            let [left, center, right]: [Rotor; 3] = rotors.try_into().map_err(|_| {
+                Error::NotInModel(
+                    self.name.to_string(),
+                    String::from("has fewer than three rotors"),
+                )
+            })?;
+
+            State::new((left, center, right), rings, initial, plugs, reflector)?
+                .with_entry_wheel(self.entry_wheel.clone())
+                .with_reflector_setting(reflector_setting)
+        }
+    }
+
+    pub fn all_models() -> Vec<Model> {
+        let qwertz = all_entry_wheels()[1].clone();
+
+        vec![
+            Model {
+                name: "I",
+                components: Components::new(
+                    all_rotors()[..5].to_vec(),
+                    all_reflectors().to_vec(),
+                    vec![EntryWheel::default()],
+                ),
+                entry_wheel: EntryWheel::default(),
+                plugboard: true,
+                settable_reflector: false,
+            },
+            Model {
+                name: "D",
+                components: Components::new(
+                    vec![
+                        builtin_rotor("I", "LPGSZMHAEOQKVXRFYBUTNICJDW", "Y"),
+                        builtin_rotor("II", "SLVGBTFXJQOHEWIRZYAMKPCNDU", "E"),
+                        builtin_rotor("III", "CJGDPSHKTURAWZXFMYNQOBVLIE", "N"),
+                    ],
+                    vec![builtin_reflector("UKW", "IMETCGFRAYSQBZXWLHKDVUPOJN")],
+                    vec![qwertz.clone()],
+                ),
+                entry_wheel: qwertz.clone(),
+                plugboard: false,
+                settable_reflector: true,
+            },
+            Model {
+                name: "Swiss-K",
+                components: Components::new(
+                    vec![
+                        builtin_rotor("I", "PEZUOHXSCVFMTBGLRINQJWAYDK", "Y"),
+                        builtin_rotor("II", "ZOUESYDKFWPCIQXHMVBLGNJRAT", "E"),
+                        builtin_rotor("III", "EHRVXGAOBQUSIMZFLYNWKTPDJC", "N"),
+                    ],
+                    vec![builtin_reflector("UKW", "IMETCGFRAYSQBZXWLHKDVUPOJN")],
+                    vec![qwertz.clone()],
+                ),
+                entry_wheel: qwertz.clone(),
+                plugboard: false,
+                settable_reflector: true,
+            },
+            Model {
+                name: "Railway",
+                components: Components::new(
+                    vec![
+                        builtin_rotor("I", "JGDQOXUSCAMIFRVTPNEWKBLZYH", "N"),
+                        builtin_rotor("II", "NTZPSFBOKMWRCJDIVLAEYUXHGQ", "E"),
+                        builtin_rotor("III", "JVIUBHTCDYAKEQZPOSGXNRMWFL", "Y"),
+                    ],
+                    vec![builtin_reflector("UKW", "QYHOGNECVPUZTFDJAXWMKISRBL")],
+                    vec![qwertz.clone()],
+                ),
+                entry_wheel: qwertz,
+                plugboard: false,
+                settable_reflector: true,
+            },
+            Model {
+                name: "T",
+                components: Components::new(
+                    vec![
+                        builtin_rotor("I", "KPTYUELOCVGRFQDANJMBSWHZXI", "WZEKQ"),
+                        builtin_rotor("II", "UPHZLWEQMTDJXCAKSOIGVBYFNR", "WZFLR"),
+                        builtin_rotor("III", "QUDLYRFEKONVZAXWHMGPJBSICT", "WZEKQ"),
+                        builtin_rotor("IV", "CIWTBKXNRESPFLYDAGVHQUOJZM", "WZFLR"),
+                        builtin_rotor("V", "UAXGISNJBVERDYLFZWTPCKOHMQ", "YCFKR"),
+                        builtin_rotor("VI", "XFUZGALVHCNYSEWQTDMRBKPIOJ", "XEIMQ"),
+                        builtin_rotor("VII", "BJVFTXPLNAYOZIKWGDQERUCHSM", "YCFKR"),
+                        builtin_rotor("VIII", "YMTPNZHWKODAJXELUQVGCBISFR", "XEIMQ"),
+                    ],
+                    vec![builtin_reflector("UKW", "GEKPBTAUMOCNILJDXZYFHWVQSR")],
+                    vec![builtin_entry_wheel("T", "KZROUQHYAIGBLWVSTDXFPNMCJE")],
+                ),
+                entry_wheel: builtin_entry_wheel("T", "KZROUQHYAIGBLWVSTDXFPNMCJE"),
+                plugboard: false,
+                settable_reflector: true,
+            },
+            Model {
+                name: "Norway",
+                components: Components::new(
+                    vec![
+                        builtin_rotor("I", "WTOKASUYVRBXJHQCPZEFMDINLG", "Q"),
+                        builtin_rotor("II", "GJLPUBSWEMCTQVHXAOFZDRKYNI", "E"),
+                        builtin_rotor("III", "JWFMHNBPUSDYTIXVZGRQLAOEKC", "V"),
+                        builtin_rotor("IV", "ESOVPZJAYQUIRHXLNFTGKDCMWB", "J"),
+                        builtin_rotor("V", "HEJXQOTZBVFDASCILWPGYNMURK", "Z"),
+                    ],
+                    vec![builtin_reflector("UKW", "MOWJYPUXNDSRAIBFVLKZGQCHET")],
+                    vec![EntryWheel::default()],
+                ),
+                entry_wheel: EntryWheel::default(),
+                plugboard: true,
+                settable_reflector: false,
+            },
+        ]
+    }
+
+    // The commercial Enigma K kept the wiring of the D, so "K" finds that model too
+    pub fn model_by_name(name: &str) -> Result<Model, Error> {
+        let wanted = if name.eq_ignore_ascii_case("K") {
+            "D"
+        } else {
+            name
+        };
+
+        all_models()
+            .into_iter()
+            .find(|m| m.name.eq_ignore_ascii_case(wanted))
+            .ok_or_else(|| Error::UnknownModel(name.to_string()))
+    }
+
+    pub const G_MODELS: [&str; 3] = ["G-312", "G-260", "G-111"];
+
+    // Each Enigma G wiring came with its own rotors and reflector, but always behind a QWERTZ
+    // entry wheel
+    pub fn g_components(name: &str) -> Result<Components, Error> {
+        let (rotors, reflector) = match name.to_ascii_uppercase().as_str() {
+            "G-312" => (
+                vec![
+                    builtin_rotor("I", "DMTWSILRUYQNKFEJCAZBPGXOHV", "SUVWZABCEFGIKLOPQ"),
+                    builtin_rotor("II", "HQZGPJTMOBLNCIFDYAWVEUSRKX", "STVYZACDFGHKMNQ"),
+                    builtin_rotor("III", "UQNTLSZFMREHDPXKIBVYGJCWOA", "UWXAEFHKMNR"),
+                ],
+                "RULQMZJSYGOCETKWDAHNBXPVIF",
+            ),
+            "G-260" => (
+                vec![
+                    builtin_rotor("I", "RCSPBLKQAUMHWYTIFZVGOJNEXD", "SUVWZABCEFGIKLOPQ"),
+                    builtin_rotor("II", "WCMIBVPJXAROSGNDLZKEYHUFQT", "STVYZACDFGHKMNQ"),
+                    builtin_rotor("III", "FVDHZELSQMAXOKYIWPGCBUJTNR", "UWXAEFHKMNR"),
+                ],
+                "IMETCGFRAYSQBZXWLHKDVUPOJN",
+            ),
+            "G-111" => (
+                vec![
+                    builtin_rotor("I", "WLRHBQUNDKJCZSEXOTMAGYFPVI", "SUVWZABCEFGIKLOPQ"),
+                    builtin_rotor("II", "TFJQAZWMHLCUIXRDYGOEVBNSKP", "STVYZACDFGHKMNQ"),
+                    builtin_rotor("V", "QTPIXWVDFRMUSLJOHCANEZKYBG", "SWZFHMQ"),
+                ],
+                "RULQMZJSYGOCETKWDAHNBXPVIF",
+            ),
+            _ => return Err(Error::UnknownModel(name.to_string())),
+        };
+
+        Ok(Components::new(
+            rotors,
+            vec![builtin_reflector("UKW", reflector)],
+            vec![all_entry_wheels()[1].clone()],
+        ))
+    }
+
+    fn definition_tables<'a>(
+        value: &'a toml::Value,
+        kind: &str,
+    ) -> Result<Vec<&'a toml::value::Table>, Error> {
+        match value.get(kind) {
+            None => Ok(Vec::new()),
+            Some(toml::Value::Array(entries)) => entries
+                .iter()
+                .map(|entry| {
+                    entry.as_table().ok_or_else(|| {
+                        Error::InvalidDefinition(format!("each {} must be a table", kind))
+                    })
+                })
+                .collect(),
+            Some(_) => Err(Error::InvalidDefinition(format!(
+                "expected [[{}]] entries",
+                kind
+            ))),
+        }
+    }
+
+    fn definition_field<'a>(
+        table: &'a toml::value::Table,
+        kind: &str,
+        field: &str,
+    ) -> Result<&'a str, Error> {
+        table
+            .get(field)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| Error::InvalidDefinition(format!("{} is missing {}", kind, field)))
+    }
+
+    // Names end up in keys, where whitespace splits the parts, '-' the rotors and ':' the
+    // options, and "D:" marks a plugged UKW-D, so a name using any of them couldn't be read back
+    fn definition_name<'a>(table: &'a toml::value::Table, kind: &str) -> Result<&'a str, Error> {
+        let name = definition_field(table, kind, "name")?;
+
+        if name.is_empty()
+            || name.starts_with("D:")
+            || name
+                .chars()
+                .any(|c| c.is_whitespace() || c == '-' || c == ':')
+        {
+            return Err(Error::InvalidDefinition(format!(
+                "{} name '{}' can't be written in a key",
+                kind, name
+            )));
+        }
+
+        Ok(name)
+    }
+
+    fn random_settings<R: Rng + ?Sized>(rng: &mut R) -> [char; 3] {
+        [
+            ALPHABET[rng.gen_range(0..MAX_WIRES)],
+            ALPHABET[rng.gen_range(0..MAX_WIRES)],
+            ALPHABET[rng.gen_range(0..MAX_WIRES)],
+        ]
+    }
+
+    fn random_plugs<R: Rng + ?Sized>(rng: &mut R, count: usize) -> Result<Plugboard, Error> {
+        if count > MAX_PLUGS {
+            return Err(Error::TooManyPlugs(count));
+        }
+
+        let mut letters = ALPHABET;
+        letters.shuffle(rng);
+
+        let pairs: Vec<(char, char)> = letters
+            .chunks(2)
+            .take(count)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+
+        Plugboard::new(&pairs)
+    }
+
+    const fn identity() -> [usize; MAX_WIRES] {
+        let mut wiring = [0; MAX_WIRES];
+        let mut i = 0;
+
+        while i < MAX_WIRES {
+            wiring[i] = i;
+            i += 1;
+        }
+
+        wiring
+    }
+
+    fn gen_wiring(encoding: &str) -> Result<[usize; MAX_WIRES], Error> {
+        let invalid = || Error::InvalidWiring(encoding.to_string());
+        let mut wiring: [usize; MAX_WIRES] = [0; MAX_WIRES];
+        let mut used = [false; MAX_WIRES];
+
+        if encoding.chars().count() != MAX_WIRES {
+            return Err(invalid());
+        }
+
+        for (i, c) in encoding.chars().enumerate() {
+            let w = checked_wire(c).map_err(|_| invalid())?;
+            if used[w] {
+                return Err(invalid());
+            }
+
+            used[w] = true;
+            wiring[i] = w;
+        }
+
+        Ok(wiring)
+    }
+
+    // The reflector, rotors, ring settings and starting positions that open every key, with
+    // the plug pairs and options after them left for each machine to read
+    struct KeyParts<'a> {
+        reflector: &'a str,
+        rotors: Vec<&'a str>,
+        rings: Vec<char>,
+        settings: Vec<char>,
+        extras: Vec<&'a str>,
+    }
+
+    impl<'a> KeyParts<'a> {
+        fn parse(key: &'a str) -> Result<KeyParts<'a>, Error> {
+            let tokens: Vec<&str> = key.split_whitespace().collect();
+            if tokens.len() < 4 {
+                return Err(Error::InvalidKey(format!(
+                    "'{}' needs a reflector, rotors, rings and settings",
+                    key
+                )));
+            }
+
+            let rotors: Vec<&str> = tokens[1].split('-').collect();
+            let rings = tokens[2]
+                .split('-')
+                .map(parse_ring)
+                .collect::<Result<Vec<char>, Error>>()?;
+
+            // A machine holds one copy of each rotor, so no key can name one twice
+            if let Some(repeated) = rotors
+                .iter()
+                .enumerate()
+                .find_map(|(i, name)| rotors[..i].contains(name).then_some(name))
+            {
+                return Err(Error::InvalidKey(format!(
+                    "'{}' uses rotor {} more than once",
+                    key, repeated
+                )));
+            }
+
+            Ok(KeyParts {
+                reflector: tokens[0],
+                rotors,
+                rings,
+                settings: tokens[3].chars().collect(),
+                extras: tokens[4..].to_vec(),
+            })
+        }
+    }
+
+    // Ring settings are numbered 01-26, though letters are accepted too
+    pub fn parse_ring(ring: &str) -> Result<char, Error> {
+        match ring.parse::<usize>() {
+            Ok(n) if (1..=MAX_WIRES).contains(&n) => Ok(ALPHABET[n - 1]),
+            Ok(_) => Err(Error::InvalidKey(format!(
+                "ring setting must be between 01 and 26: {}",
+                ring
+            ))),
+            Err(_) => {
+                let mut letters = ring.chars();
+                match (letters.next(), letters.next()) {
+                    (Some(c), None) => Ok(unwire(checked_wire(c)?)),
+                    _ => Err(Error::InvalidKey(format!("invalid ring setting: {}", ring))),
+                }
+            }
+        }
+    }
+
+    fn builtin_rotor(name: &str, encoding: &str, notches: &str) -> Rotor {
+        Rotor::new(name, encoding, notches).expect("Built-in rotor wirings are valid")
+    }
+
+    fn builtin_reflector(name: &str, encoding: &str) -> Reflector {
+        Reflector::new(name, encoding).expect("Built-in reflector wirings are valid")
+    }
+
+    fn builtin_entry_wheel(name: &str, encoding: &str) -> EntryWheel {
+        EntryWheel::new(name, encoding).expect("Built-in entry wheel wirings are valid")
+    }
+
+    fn inv(wiring: [usize; MAX_WIRES]) -> [usize; MAX_WIRES] {
+        let mut iwiring: [usize; MAX_WIRES] = [0; MAX_WIRES];
+
+        for i in 0..MAX_WIRES {
+            iwiring[wiring[i]] = i;
+        }
+
+        iwiring
+    }
+
+    fn split_plug_pairs(s: &str) -> Result<Vec<(char, char)>, Error> {
+        let mut pairs = Vec::new();
+
+        for pair in s.split_whitespace() {
+            let letters: Vec<char> = pair.chars().collect();
+            if letters.len() != 2 {
+                return Err(Error::InvalidPlugPair(pair.to_string()));
+            }
+
+            pairs.push((letters[0], letters[1]));
+        }
+
+        Ok(pairs)
+    }
+
+    fn checked_wire(c: char) -> Result<usize, Error> {
+        if c.is_ascii_alphabetic() {
+            Ok(wire(c.to_ascii_uppercase()))
+        } else {
+            Err(Error::InvalidLetter(c))
+        }
+    }
+
+    // Shared with the state sets so bad rings are refused before any state is built
+    pub(crate) fn checked_rings(rings: [char; 3]) -> Result<[char; 3], Error> {
+        Ok([
+            unwire(checked_wire(rings[0])?),
+            unwire(checked_wire(rings[1])?),
+            unwire(checked_wire(rings[2])?),
+        ])
+    }
+
+    fn wire(c: char) -> usize {
+        ((c as u8) - b'A') as usize
+    }
+
+    fn unwire(i: usize) -> char {
+        (i as u8 + b'A') as char
+    }
+
+    #[cfg(feature = "serde")]
+    fn encoding(wiring: &[usize; MAX_WIRES]) -> String {
+        wiring.iter().map(|w| unwire(*w)).collect()
+    }
+
+    // Components are serialized by their letter wirings and rebuilt through the regular
+    // constructors, so anything read back in gets the same validation as a definition file
+    #[cfg(feature = "serde")]
+    #[derive(Serialize, Deserialize)]
+    struct RotorDef {
+        name: String,
+        wiring: String,
+        #[serde(default)]
+        notches: String,
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<RotorDef> for Rotor {
+        type Error = Error;
+
+        fn try_from(def: RotorDef) -> Result<Rotor, Error> {
+            Rotor::new(&def.name, &def.wiring, &def.notches)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<Rotor> for RotorDef {
+        fn from(rotor: Rotor) -> RotorDef {
+            RotorDef {
+                wiring: encoding(&rotor.wiring),
+                notches: (0..MAX_WIRES)
+                    .filter(|&i| rotor.notches[i])
+                    .map(unwire)
+                    .collect(),
+                name: rotor.name,
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Serialize, Deserialize)]
+    struct WiringDef {
+        name: String,
+        wiring: String,
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<WiringDef> for Reflector {
+        type Error = Error;
+
+        fn try_from(def: WiringDef) -> Result<Reflector, Error> {
+            Reflector::new(&def.name, &def.wiring)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<Reflector> for WiringDef {
+        fn from(reflector: Reflector) -> WiringDef {
+            WiringDef {
+                wiring: encoding(&reflector.wiring),
+                name: reflector.name,
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<WiringDef> for EntryWheel {
+        type Error = Error;
+
+        fn try_from(def: WiringDef) -> Result<EntryWheel, Error> {
+            EntryWheel::new(&def.name, &def.wiring)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<EntryWheel> for WiringDef {
+        fn from(entry_wheel: EntryWheel) -> WiringDef {
+            WiringDef {
+                wiring: encoding(&entry_wheel.wiring),
+                name: entry_wheel.name,
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<String> for Plugboard {
+        type Error = Error;
+
+        fn try_from(pairs: String) -> Result<Plugboard, Error> {
+            pairs.parse()
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<Plugboard> for String {
+        fn from(plugs: Plugboard) -> String {
+            plugs.to_string()
+        }
+    }
+
+    // Rotors run left to right with the Greek rotor first on an M4, and rings and settings
+    // have a letter for each rotor. The key is only written out for people reading the file
+    #[cfg(feature = "serde")]
+    #[derive(Serialize, Deserialize)]
+    struct StateDef {
+        #[serde(default, skip_deserializing)]
+        key: String,
+        reflector: Reflector,
+        rotors: Vec<Rotor>,
+        rings: String,
+        settings: String,
+        plugs: Plugboard,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uhr: Option<Uhr>,
+        #[serde(default)]
+        entry_wheel: EntryWheel,
+        #[serde(default = "default_reflector_setting")]
+        reflector_setting: char,
+        position: usize,
+    }
+
+    #[cfg(feature = "serde")]
+    fn default_reflector_setting() -> char {
+        'A'
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Serialize, Deserialize)]
+    struct UhrDef {
+        pairs: String,
+        dial: usize,
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<UhrDef> for Uhr {
+        type Error = Error;
+
+        fn try_from(def: UhrDef) -> Result<Uhr, Error> {
+            Uhr::from_pairs(&def.pairs, def.dial)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<Uhr> for UhrDef {
+        fn from(uhr: Uhr) -> UhrDef {
+            UhrDef {
+                pairs: uhr.to_string(),
+                dial: uhr.dial,
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl TryFrom<StateDef> for State {
+        type Error = Error;
+
+        fn try_from(def: StateDef) -> Result<State, Error> {
+            let rings: Vec<char> = def.rings.chars().collect();
+            let settings: Vec<char> = def.settings.chars().collect();
+
+            if rings.len() != def.rotors.len() || settings.len() != def.rotors.len() {
+                return Err(Error::InvalidKey(
+                    "state needs a ring and setting for each rotor".to_string(),
+                ));
+            }
+
+            let state = match <[Rotor; 3]>::try_from(def.rotors) {
+                Ok([left, center, right]) => State::new(
+                    (left, center, right),
+                    [rings[0], rings[1], rings[2]],
+                    [settings[0], settings[1], settings[2]],
+                    def.plugs,
+                    def.reflector,
+                )?,
+                Err(rotors) => match <[Rotor; 4]>::try_from(rotors) {
+                    Ok([greek, left, center, right]) => State::new_m4(
+                        (greek, left, center, right),
+                        [rings[0], rings[1], rings[2], rings[3]],
+                        [settings[0], settings[1], settings[2], settings[3]],
+                        def.plugs,
+                        def.reflector,
+                    )?,
+                    Err(_) => {
+                        return Err(Error::InvalidKey(
+                            "state needs three or four rotors".to_string(),
+                        ))
+                    }
+                },
+            };
+
+            let state = match def.uhr {
+                Some(uhr) => state.with_uhr(uhr),
+                None => state,
+            };
+
+            let mut state = state
+                .with_entry_wheel(def.entry_wheel)
+                .with_reflector_setting(def.reflector_setting)?;
+            state.seek(def.position);
+            Ok(state)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl From<State> for StateDef {
+        fn from(state: State) -> StateDef {
+            let mut rings: String = state.rings.iter().map(|r| unwire(*r)).collect();
+            let mut settings: String = state.setting.iter().map(|s| unwire(*s)).collect();
+
+            if state.greek_rotor.is_some() {
+                rings.insert(0, unwire(state.greek_ring));
+                settings.insert(0, unwire(state.greek_setting));
+            }
+
+            StateDef {
+                key: state.to_string(),
+                rings,
+                settings,
+                plugs: state.plug_board,
+                uhr: state.uhr,
+                entry_wheel: state.entry_wheel,
+                reflector_setting: unwire(state.reflector_setting),
+                position: state.position,
+                rotors: state
+                    .greek_rotor
+                    .into_iter()
+                    .chain([state.left_rotor, state.center_rotor, state.right_rotor])
+                    .collect(),
+                reflector: state.reflector,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn state(key: &str) -> State {
+            State::from_key(key, &Components::builtin()).unwrap()
+        }
+
+        #[test]
+        fn encrypts_published_vector() {
+            assert_eq!(state("B I-II-III 01-01-01 AAA").encode("AAAAA"), "BDZGO");
+        }
+
+        #[test]
+        fn middle_rotor_double_steps() {
+            let mut state = state("B I-II-III 01-01-01 ADU");
+            state.encode("AAA");
+            assert_eq!(state.window(), ['B', 'F', 'X']);
+        }
+
+        #[test]
+        fn cycle_length_counts_double_stepping() {
+            assert_eq!(state("B I-II-III 01-01-01 AAA").cycle_length(), 16900);
+        }
+
+        #[test]
+        fn decrypts_barbarossa_message() {
+            let mut state = state("B II-IV-V 02-21-12 BLA AV BS CG DL FU HZ IN KM OW RX");
+            assert_eq!(
+                state.encode(
+                    "EDPUDNRGYSZRCXNUYTPOMRMBOFKTBZREZKMLXLVEFGUEYSIOZVEQMIKUBPMMYLKLTTDEISMDICAGYKUA\
+                     CTCDOMOHWXMUUIAUBSTSLRNBZSZWNRFXWFYSSXJZVIJHIDISHPRKLKAYUPADTXQSPINQMATLPIFSVKDA\
+                     SCTACDPBOPVHJK"
+                ),
+                "AUFKLXABTEILUNGXVONXKURTINOWAXKURTINOWAXNORDWESTLXSEBEZXSEBEZXUAFFLIEGERSTRASZERI\
+                 QTUNGXDUBROWKIXDUBROWKIXOPOTSCHKAXOPOTSCHKAXUMXEINSAQTDREINULLXUHRANGETRETENXANG\
+                 RIFFXINFXRGTX"
+            );
+        }
+
+        #[test]
+        fn decrypts_u534_message() {
+            let mut state =
+                state("B-Thin Beta-II-IV-I 01-01-01-22 VJNA AT BL DF GJ HM NW OP QY RZ VX");
+            assert_eq!(
+                state.encode(
+                    "NCZWVUSXPNYMINHZXMQXSFWXWLKJAHSHNMCOCCAKUQPMKCSMHKSEINJUSBLKIOSXCKUBHMLLXCSJUSRR\
+                     DVKOHULXWCCBGVLIYXEOAHXRHKKFVDREWEZLXOBAFGYUJQUKGRTVUKAMEURBVEKSUHHVOYHABCJWMAKL\
+                     FKLMYFVNRIZRVVRTKOFDANJMOLBGFFLEOPRGTFLVRHOWOPBEKVWMUQFMPWPARMFHAGKXIIBG"
+                ),
+                "VONVONJLOOKSJHFFTTTEINSEINSDREIZWOYYQNNSNEUNINHALTXXBEIANGRIFFUNTERWASSERGEDRUEC\
+                 KTYWABOSXLETZTERGEGNERSTANDNULACHTDREINULUHRMARQUANTONJOTANEUNACHTSEYHSDREIYZWOZ\
+                 WONULGRADYACHTSMYSTOSSENACHXEKNSVIERMBFAELLTYNNNNNNOOOVIERYSICHTEINSNULL"
+            );
+        }
+
+        // Every position early on, then a sample reaching well past the 26^3 steps after which
+        // seek works from the stepping cycle
+        fn seek_matches_stepping(key: &str) {
+            let mut stepped = state(key);
+            let mut sought = stepped.clone();
+
+            for position in 0..40_000 {
+                if position < 2_000 || position % 37 == 0 {
+                    sought.seek(position);
+                    assert_eq!(sought.window(), stepped.window(), "{} at {}", key, position);
+                    assert_eq!(sought.position(), position);
+                    assert_eq!(stepped.window_at(position), stepped.window());
+
+                    let mut next = sought.clone();
+                    assert_eq!(next.encrypt('A'), stepped.clone().encrypt('A'));
+                }
+                stepped.encrypt('A');
+            }
+        }
+
+        #[test]
+        fn seek_matches_stepping_with_double_notches() {
+            seek_matches_stepping("B VI-VII-VIII 03-11-20 YLZ AB CD EF");
+        }
+
+        #[test]
+        fn seek_matches_stepping_on_m4() {
+            seek_matches_stepping("C-Thin Gamma-VIII-I-VI 05-14-22-09 RMZL AT BL DF GJ HM");
+        }
+
+        #[test]
+        fn rewind_returns_to_earlier_windows() {
+            let mut state = state("B I-II-III 01-01-01 ADU");
+            state.encode("AAAAA");
+            state.rewind(2);
+            assert_eq!(state.position(), 3);
+            assert_eq!(state.window(), ['B', 'F', 'X']);
+
+            state.rewind(10);
+            assert_eq!(state.position(), 0);
+            assert_eq!(state.window(), ['A', 'D', 'U']);
+        }
+
+        #[test]
+        fn keys_round_trip_through_display() {
+            for key in [
+                "B I-II-III 01-01-01 AAA",
+                "C V-VIII-II 26-09-14 QEV AB CD EF GH IJ KL MN OP QR ST",
+                "B I-II-III 01-01-01 AAA AB CD EF GH IJ KL MN OP QR ST UHR:13",
+                "A II-III-I 04-05-06 XYZ ETW:QWERTZ",
+                "B III-II-I 01-01-01 AAA UKW:Q",
+                "B-Thin Beta-II-IV-I 01-01-01-22 VJNA AT BL DF GJ HM NW OP QY RZ VX",
+            ] {
+                let state: State = key.parse().unwrap();
+                assert_eq!(state.to_string(), key);
+
+                let read_back: State = state.to_string().parse().unwrap();
+                assert_eq!(read_back.to_string(), key);
+                assert_eq!(
+                    read_back.clone().encode("ENIGMAROUNDTRIP"),
+                    state.clone().encode("ENIGMAROUNDTRIP")
+                );
+            }
+        }
+
+        #[test]
+        fn keys_write_rings_as_numbers() {
+            let state: State = "B I-II-III A-b-Z AAA".parse().unwrap();
+            assert_eq!(state.to_string(), "B I-II-III 01-02-26 AAA");
+        }
+
+        #[test]
+        fn malformed_keys_are_rejected() {
+            for key in [
+                "B I-II-III 01-01 AAA",
+                "B I-II-III 01-01-01 AA",
+                "B I-II-III 01-01-01-01 AAA",
+                "B I-II-III 00-01-01 AAA",
+                "B I-II-III 27-01-01 AAA",
+                "B I-I-III 01-01-01 AAA",
+                "B I-II-III 01-01-01",
+                "B I-II-III 01-01-01 AAA XYZ:1",
+            ] {
+                assert!(
+                    matches!(key.parse::<State>(), Err(Error::InvalidKey(_))),
+                    "{}",
+                    key
+                );
+            }
+
+            // Thin reflectors only fit beside a Greek rotor, and the wide ones only without
+            assert_eq!(
+                "B-Thin I-II-III 01-01-01 AAA".parse::<State>().err(),
+                Some(Error::UnknownReflector("B-Thin".to_string()))
+            );
+            assert_eq!(
+                "B Beta-II-IV-I 01-01-01-01 AAAA".parse::<State>().err(),
+                Some(Error::UnknownReflector("B".to_string()))
+            );
+        }
+
+        #[test]
+        fn ukw_d_names_one_wiring_one_way() {
+            let given = Reflector::ukw_d("AF-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WB").unwrap();
+            assert_eq!(given.name(), "D:AF-BW-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX");
+
+            let reordered = Reflector::ukw_d("xu zt sr po qn ml ki vg ed hc fa bw").unwrap();
+            assert_eq!(reordered, given);
+            assert_eq!(reflector_by_name(given.name()).unwrap(), given);
+        }
+
+        #[test]
+        fn ukw_d_rejects_bad_pairs() {
+            assert!(matches!(
+                Reflector::ukw_d("AF-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert!(matches!(
+                Reflector::ukw_d("AF-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WB-JY"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert!(matches!(
+                Reflector::ukw_d("AJ-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WB"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert!(matches!(
+                Reflector::ukw_d("AF-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WY"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert!(matches!(
+                Reflector::ukw_d("AF-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WA"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert!(matches!(
+                Reflector::ukw_d("AA-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WB"),
+                Err(Error::InvalidUkwD(_))
+            ));
+            assert_eq!(
+                Reflector::ukw_d("A1-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX-WB").err(),
+                Some(Error::InvalidLetter('1'))
+            );
+        }
+
+        #[test]
+        fn components_reject_names_keys_cannot_hold() {
+            for name in ["", "X-1", "My UKW", "UKW:2", "D:AB"] {
+                let toml = format!(
+                    "[[reflector]]\nname = \"{}\"\nwiring = \"YRUHQSLDPXNGOKMIEBFZCWVJAT\"",
+                    name
+                );
+                assert!(
+                    matches!(
+                        Components::from_toml(&toml),
+                        Err(Error::InvalidDefinition(_))
+                    ),
+                    "{:?}",
+                    name
+                );
+            }
+
+            let components = Components::from_toml(
+                "[[rotor]]\nname = \"X1\"\nwiring = \"EKMFLGDQVZNTOWYHXUSPAIBRCJ\"\nnotches = \"Q\"",
+            )
+            .unwrap();
+            assert!(components.rotor_by_name("X1").is_ok());
+        }
+
+        #[test]
+        fn plugboards_parse_any_case() {
+            let plugs: Plugboard = "ab Cd eF".parse().unwrap();
+            assert_eq!(plugs.pairs(), vec![('A', 'B'), ('C', 'D'), ('E', 'F')]);
+            assert_eq!(plugs.to_string(), "AB CD EF");
+            assert_eq!("".parse::<Plugboard>().unwrap(), NO_PLUGS);
+        }
+
+        #[test]
+        fn plugboards_reject_bad_pairs() {
+            assert_eq!(
+                "AB CC".parse::<Plugboard>().err(),
+                Some(Error::SelfPlug('C'))
+            );
+            assert_eq!(
+                "AB CD BE".parse::<Plugboard>().err(),
+                Some(Error::DuplicatePlug('B'))
+            );
+            assert_eq!(
+                "AB CD EF GH IJ KL MN OP QR ST UV WX YZ AC"
+                    .parse::<Plugboard>()
+                    .err(),
+                Some(Error::TooManyPlugs(14))
+            );
+            assert_eq!(
+                "AB,CD".parse::<Plugboard>().err(),
+                Some(Error::InvalidPlugPair("AB,CD".to_string()))
+            );
+            assert_eq!(
+                "ABC".parse::<Plugboard>().err(),
+                Some(Error::InvalidPlugPair("ABC".to_string()))
+            );
+            assert_eq!(
+                "A1".parse::<Plugboard>().err(),
+                Some(Error::InvalidLetter('1'))
+            );
+        }
+
+        #[cfg(feature = "serde")]
+        #[test]
+        fn stepped_states_survive_json() {
+            for key in [
+                "B-Thin Beta-II-IV-I 01-01-01-22 VJNA AT BL DF GJ HM NW OP QY RZ VX",
+                "B I-II-III 05-01-17 QEV AB CD EF GH IJ KL MN OP QR ST UHR:27",
+                "D:AF-BW-CH-DE-GV-IK-LM-NQ-OP-RS-TZ-UX II-III-I 01-02-03 XYZ ETW:QWERTZ UKW:G",
+            ] {
+                let mut stepped = state(key);
+                stepped.encode("SERIALISEDAFTERSEVERALKEYPRESSES");
+
+                let json = serde_json::to_string(&stepped).unwrap();
+                let mut read_back: State = serde_json::from_str(&json).unwrap();
+                assert_eq!(read_back.to_string(), key);
+                assert_eq!(read_back.position(), stepped.position());
+                assert_eq!(read_back.window(), stepped.window());
+                assert_eq!(read_back.greek_window(), stepped.greek_window());
+                assert_eq!(read_back.encode("ANDCARRYON"), stepped.encode("ANDCARRYON"));
+            }
+        }
+
+        const UHR_TEST_PAIRS: &str = "AB CD EF GH IJ KL MN OP QR ST";
+
+        #[test]
+        fn uhr_at_dial_zero_plugs_like_cables() {
+            let uhr = Uhr::from_pairs(UHR_TEST_PAIRS, 0).unwrap();
+            let cables: Plugboard = UHR_TEST_PAIRS.parse().unwrap();
+            assert_eq!(uhr.forward, cables.wiring);
+            assert_eq!(uhr.backward, cables.wiring);
+        }
+
+        #[test]
+        fn uhr_swaps_in_pairs_every_fourth_dial() {
+            for dial in (4..UHR_POSITIONS).step_by(4) {
+                let uhr = Uhr::from_pairs(UHR_TEST_PAIRS, dial).unwrap();
+                assert_eq!(uhr.forward, uhr.backward, "dial {}", dial);
+                for letter in 0..20 {
+                    assert_ne!(uhr.forward[letter], letter, "dial {}", dial);
+                }
+            }
+
+            let uhr = Uhr::from_pairs(UHR_TEST_PAIRS, 1).unwrap();
+            assert_ne!(uhr.forward, uhr.backward);
+        }
+
+        // At dial 1 contact 0 (1a thick) meets contact 31 - 1 = 30, the thin pin of 3b, and
+        // contact 4 (1b thick) is reached from contact 30, the thin pin of 8a
+        #[test]
+        fn uhr_follows_published_wiring() {
+            let uhr = Uhr::from_pairs(UHR_TEST_PAIRS, 1).unwrap();
+            assert_eq!(unwire(uhr.forward[wire('A')]), 'F');
+            assert_eq!(unwire(uhr.forward[wire('B')]), 'O');
+
+            let mut state = state("B I-II-III 01-01-01 AAA AB CD EF GH IJ KL MN OP QR ST UHR:01");
+            assert_eq!(state.encode("HELLOWORLD"), "XJVRXFCMXM");
+        }
+
+        fn enigma_g(model: &str, key: &str) -> EnigmaG {
+            EnigmaG::from_key(key, &g_components(model).unwrap()).unwrap()
+        }
+
+        #[test]
+        fn encrypts_each_g_wiring() {
+            for (model, key, ciphertext, window) in [
+                (
+                    "G-312",
+                    "UKW I-II-III 07-13-22 XQSU",
+                    "HRJSWDBJNXJMBPBRENKC",
+                    ['B', 'W', 'C', 'O'],
+                ),
+                (
+                    "G-260",
+                    "UKW III-I-II 07-13-22 XQSU",
+                    "NVDBBHLNZPOPTJLTRYID",
+                    ['B', 'Y', 'E', 'O'],
+                ),
+                (
+                    "G-111",
+                    "UKW II-V-I 07-13-22 XQSU",
+                    "ZDWAYLQZLURHWNNEDHPT",
+                    ['Z', 'T', 'F', 'O'],
+                ),
+            ] {
+                let mut machine = enigma_g(model, key);
+                assert_eq!(
+                    machine.encode("GEHEIMEKOMMANDOSACHE"),
+                    ciphertext,
+                    "{}",
+                    model
+                );
+                assert_eq!(machine.window(), window, "{}", model);
+            }
+        }
+
+        #[test]
+        fn g_cog_wheels_never_double_step() {
+            // The right wheel's notch carries the middle wheel on
+            let mut machine = enigma_g("G-312", "UKW I-II-III 01-01-01 AABU");
+            machine.encrypt('A');
+            assert_eq!(machine.window(), ['A', 'A', 'C', 'V']);
+
+            // A middle wheel resting on a notch stays put while the right wheel is off one
+            let mut machine = enigma_g("G-312", "UKW I-II-III 01-01-01 ABAB");
+            machine.encrypt('A');
+            assert_eq!(machine.window(), ['A', 'B', 'A', 'C']);
+        }
+
+        #[test]
+        fn g_reflector_turns_when_every_wheel_carries() {
+            let mut machine = enigma_g("G-312", "UKW I-II-III 01-01-01 ASSU");
+            machine.encrypt('A');
+            assert_eq!(machine.window(), ['B', 'T', 'T', 'V']);
+
+            // Left wheel R is off its notches, so the carry stops short of the reflector
+            let mut machine = enigma_g("G-312", "UKW I-II-III 01-01-01 ARSU");
+            machine.encrypt('A');
+            assert_eq!(machine.window(), ['A', 'S', 'T', 'V']);
+        }
+
+        #[test]
+        fn g_keys_reject_repeated_rotors() {
+            assert!(EnigmaG::from_key(
+                "UKW I-I-III 01-01-01 AAAA",
+                &g_components("G-312").unwrap()
+            )
+            .is_err());
+        }
+    }
+}
+
+pub mod factory {
+    use crate::machine::*;
+
+    pub struct StateSet {
+        pub count: usize,
+        rotors: Vec<Rotor>,
+        selected_rotors: (usize, usize, usize),
+        reflectors: Vec<Reflector>,
+        selected_reflector: usize,
+        reflector_settings: usize,
+        selected_reflector_setting: usize,
+        selected_settings: (usize, usize, usize),
+        rings: [char; 3],
+        plugs: Plugboard,
+        entry_wheel: EntryWheel,
+    }
+
+    impl StateSet {
+        pub fn new() -> StateSet {
+            StateSet::unchecked(
+                all_rotors().to_vec(),
+                all_reflectors().to_vec(),
+                ['A', 'A', 'A'],
+                NO_PLUGS,
+            )
+        }
+
+        pub fn with_rings(rings: [char; 3]) -> Result<StateSet, Error> {
+            StateSet::with_reflectors(all_reflectors().to_vec(), rings)
+        }
+
+        pub fn with_reflectors(
+            reflectors: Vec<Reflector>,
+            rings: [char; 3],
+        ) -> Result<StateSet, Error> {
+            StateSet::with_components(all_rotors().to_vec(), reflectors, rings, NO_PLUGS)
+        }
+
+        pub fn with_components(
+            rotors: Vec<Rotor>,
+            reflectors: Vec<Reflector>,
+            rings: [char; 3],
+            plugs: Plugboard,
+        ) -> Result<StateSet, Error> {
+            Ok(StateSet::unchecked(
+                rotors,
+                reflectors,
+                checked_rings(rings)?,
+                plugs,
+            ))
+        }
+
+        // Every state is built from these rings, so they must already be letters A-Z
+        fn unchecked(
+            rotors: Vec<Rotor>,
+            reflectors: Vec<Reflector>,
+            rings: [char; 3],
+            plugs: Plugboard,
+        ) -> StateSet {
+            StateSet {
+                count: 0,
+                rotors,
+                selected_rotors: (0, 1, 2),
+                reflectors,
+                selected_reflector: 0,
+                reflector_settings: 1,
+                selected_reflector_setting: 0,
+                selected_settings: (0, 0, 0),
+                rings,
+                plugs,
+                entry_wheel: EntryWheel::default(),
+            }
+        }
+
+        pub fn with_entry_wheel(mut self, entry_wheel: EntryWheel) -> StateSet {
+            self.entry_wheel = entry_wheel;
+            self
+        }
+
+        // Machines whose reflector can be turned by hand need each of its positions searched
+        // too, which multiplies the states by 26
+        pub fn with_settable_reflector(mut self, settable: bool) -> StateSet {
+            self.reflector_settings = if settable { MAX_WIRES } else { 1 };
+            self
+        }
+
+        fn shift(&mut self) {
+            self.count += 1;
+
+            let (mut left_set, mut center_set, mut right_set) = self.selected_settings;
+
+            right_set += 1;
+            if right_set == MAX_WIRES {
+                center_set += 1;
+                if center_set == MAX_WIRES {
+                    left_set += 1;
+                    if left_set == MAX_WIRES {
+                        let mut reflector_setting = self.selected_reflector_setting;
+
+                        reflector_setting += 1;
+                        if reflector_setting == self.reflector_settings {
+                            let mut reflector = self.selected_reflector;
+
+                            reflector += 1;
+                            if reflector == self.reflectors.len() {
+                                self.selected_rotors = self.next_rotors();
+                            }
+
+                            self.selected_reflector = reflector % self.reflectors.len();
+                        }
+
+                        self.selected_reflector_setting =
+                            reflector_setting % self.reflector_settings;
+                    }
+                }
+            }
+
+            self.selected_settings = (
+                left_set % MAX_WIRES,
+                center_set % MAX_WIRES,
+                right_set % MAX_WIRES,
+            );
+        }
+
+        // Rotor orders are walked in lexicographic order, skipping any that reuse a rotor
+        fn next_rotors(&self) -> (usize, usize, usize) {
+            let count = self.rotors.len();
+            let (mut left, mut center, mut right) = self.selected_rotors;
+
+            loop {
+                right += 1;
+                if right == count {
+                    right = 0;
+                    center += 1;
+                    if center == count {
+                        center = 0;
+                        left = (left + 1) % count;
+                    }
+                }
+
+                if left != center && left != right && center != right {
+                    return (left, center, right);
+                }
+            }
+        }
+
+        fn pick_rotors(&self) -> (Rotor, Rotor, Rotor) {
+            let (left, center, right) = self.selected_rotors;
+
+            (
+                self.rotors[left].clone(),
+                self.rotors[center].clone(),
+                self.rotors[right].clone(),
+            )
+        }
+
+        fn pick_reflector(&self) -> Reflector {
+            self.reflectors[self.selected_reflector].clone()
+        }
+
+        fn pick_initial_settings(&self) -> [char; 3] {
+            let (left_set, center_set, right_set) = self.selected_settings;
+
+            [
+                ALPHABET[left_set],
+                ALPHABET[center_set],
+                ALPHABET[right_set],
+            ]
+        }
+
+        pub fn total(&self) -> usize {
+            let rotors = self.rotors.len();
+
+            if rotors < 3 {
+                return 0;
+            }
+
+            MAX_WIRES
+                * MAX_WIRES
+                * MAX_WIRES
+                * self.reflectors.len()
+                * self.reflector_settings
+                * rotors
+                * (rotors - 1)
+                * (rotors - 2)
+        }
+
+        fn done(&self) -> bool {
+            self.count >= self.total()
+        }
+    }
+
+    impl Iterator for StateSet {
+        type Item = State;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done() {
+                None
+            } else {
+                let next_state = Some(
+                    State::new(
+                        self.pick_rotors(),
+                        self.rings,
+                        self.pick_initial_settings(),
+                        self.plugs,
+                        self.pick_reflector(),
+                    )
+                    .expect("StateSet rings are checked when it is built")
+                    .with_entry_wheel(self.entry_wheel.clone())
+                    .with_reflector_setting(ALPHABET[self.selected_reflector_setting])
+                    .expect("reflector settings are letters A-Z"),
+                );
+
+                self.shift();
+
+                next_state
+            }
+        }
+    }
+
+    impl Default for StateSet {
+        fn default() -> StateSet {
+            StateSet::new()
+        }
+    }
+
+    pub struct M4StateSet {
+        pub count: usize,
+        greek_rotors: [Rotor; MAX_GREEK_ROTORS],
+        selected_greek: usize,
+        greek_setting: usize,
+        states: StateSet,
+        rings: [char; 3],
+        plugs: Plugboard,
+    }
+
+    impl M4StateSet {
+        pub fn new() -> M4StateSet {
+            M4StateSet::unchecked(['A', 'A', 'A'], NO_PLUGS)
+        }
+
+        pub fn with_rings(rings: [char; 3]) -> Result<M4StateSet, Error> {
+            M4StateSet::with_plugs(rings, NO_PLUGS)
+        }
+
+        pub fn with_plugs(rings: [char; 3], plugs: Plugboard) -> Result<M4StateSet, Error> {
+            Ok(M4StateSet::unchecked(checked_rings(rings)?, plugs))
+        }
+
+        fn unchecked(rings: [char; 3], plugs: Plugboard) -> M4StateSet {
+            M4StateSet {
+                count: 0,
+                greek_rotors: all_greek_rotors(),
+                selected_greek: 0,
+                greek_setting: 0,
+                states: M4StateSet::three_rotor_states(rings),
+                rings,
+                plugs,
+            }
+        }
+
+        fn three_rotor_states(rings: [char; 3]) -> StateSet {
+            StateSet::unchecked(
+                all_rotors().to_vec(),
+                all_thin_reflectors().to_vec(),
+                rings,
+                NO_PLUGS,
+            )
+        }
+
+        fn shift(&mut self) {
+            self.count += 1;
+            self.states.shift();
+
+            if self.states.done() {
+                self.states = M4StateSet::three_rotor_states(self.rings);
+
+                self.greek_setting += 1;
+                if self.greek_setting == MAX_WIRES {
+                    self.greek_setting = 0;
+                    self.selected_greek += 1;
+                }
+            }
+        }
+
+        pub fn total(&self) -> usize {
+            MAX_GREEK_ROTORS * MAX_WIRES * self.states.total()
+        }
+
+        fn done(&self) -> bool {
+            self.count >= self.total()
+        }
+    }
+
+    impl Iterator for M4StateSet {
+        type Item = State;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done() {
+                None
+            } else {
+                let (left, center, right) = self.states.pick_rotors();
+                let [left_set, center_set, right_set] = self.states.pick_initial_settings();
+
+                // The Greek rotor never steps, so its ring setting is covered by its position
+                let next_state = Some(
+                    State::new_m4(
+                        (
+                            self.greek_rotors[self.selected_greek].clone(),
+                            left,
+                            center,
+                            right,
+                        ),
+                        ['A', self.rings[0], self.rings[1], self.rings[2]],
+                        [
+                            ALPHABET[self.greek_setting],
+                            left_set,
+                            center_set,
+                            right_set,
+                        ],
+                        self.plugs,
+                        self.states.pick_reflector(),
+                    )
+                    .expect("M4StateSet rings are checked when it is built"),
+                );
+
+                self.shift();
+
+                next_state
+            }
+        }
+    }
+
+    impl Default for M4StateSet {
+        fn default() -> M4StateSet {
+            M4StateSet::new()
+        }
+    }
+}
+
+pub mod stream {
+    use crate::machine::State;
+    use std::io::{self, Read, Write};
+
+    pub struct EncoderReader<R: Read> {
+        inner: R,
+        machine: State,
+        keep_case: bool,
+    }
+
+    impl<R: Read> EncoderReader<R> {
+        pub fn new(inner: R, machine: State) -> EncoderReader<R> {
+            EncoderReader {
+                inner,
+                machine,
+                keep_case: false,
+            }
+        }
+
+        // Gives each letter back in the case it came in, so encoding twice restores a file
+        // byte for byte
+        pub fn with_case_kept(mut self) -> EncoderReader<R> {
+            self.keep_case = true;
+            self
+        }
+
+        pub fn machine(&self) -> &State {
+            &self.machine
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    impl<R: Read> Read for EncoderReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let bytes = self.inner.read(buf)?;
+            match self.keep_case {
+                true => encode_bytes_keeping_case(&mut self.machine, &mut buf[..bytes]),
+                false => encode_bytes(&mut self.machine, &mut buf[..bytes]),
+            }
+
+            Ok(bytes)
+        }
+    }
+
+    pub struct EncoderWriter<W: Write> {
+        inner: W,
+        machine: State,
+        buffer: Vec<u8>,
+    }
+
+    impl<W: Write> EncoderWriter<W> {
+        pub fn new(inner: W, machine: State) -> EncoderWriter<W> {
+            EncoderWriter {
+                inner,
+                machine,
+                buffer: Vec::new(),
+            }
+        }
+
+        pub fn machine(&self) -> &State {
+            &self.machine
+        }
+
+        pub fn into_inner(self) -> W {
+            self.inner
+        }
+    }
+
+    impl<W: Write> Write for EncoderWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buffer.clear();
+            self.buffer.extend_from_slice(buf);
+            encode_bytes(&mut self.machine, &mut self.buffer);
+
+            self.inner.write_all(&self.buffer)?;
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    // Bytes are handled like State::encode handles characters: ASCII letters are encrypted
+    // (and upper cased) while everything else, including non-ASCII UTF-8, passes through.
+    pub fn encode_bytes(machine: &mut State, bytes: &mut [u8]) {
+        for b in bytes.iter_mut().filter(|b| b.is_ascii_alphabetic()) {
+            *b = machine.encrypt(*b as char) as u8;
+        }
+    }
+
+    // Like encode_bytes, but lower case letters come out lower case
+    pub fn encode_bytes_keeping_case(machine: &mut State, bytes: &mut [u8]) {
+        for b in bytes.iter_mut().filter(|b| b.is_ascii_alphabetic()) {
+            let encrypted = machine.encrypt(*b as char) as u8;
+            *b = match b.is_ascii_lowercase() {
+                true => encrypted.to_ascii_lowercase(),
+                false => encrypted,
+            };
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::machine::Components;
+
+        fn machine() -> State {
+            State::from_key(
+                "B II-IV-V 02-21-12 BLA AV BS CG DL FU HZ IN KM OW RX",
+                &Components::builtin(),
+            )
+            .unwrap()
+        }
+
+        fn read_all(mut reader: impl Read) -> Vec<u8> {
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            out
+        }
+
+        const TEXT: &str = "Stepping carries on across every buffer boundary, 1 2 3!";
+
+        #[test]
+        fn small_writes_match_one_encode() {
+            let expected = machine().encode(TEXT);
+
+            for chunk in [1, 2, 5, 7] {
+                let mut writer = EncoderWriter::new(Vec::new(), machine());
+                for piece in TEXT.as_bytes().chunks(chunk) {
+                    writer.write_all(piece).unwrap();
+                }
+                assert_eq!(
+                    writer.machine().position(),
+                    TEXT.bytes().filter(u8::is_ascii_alphabetic).count()
+                );
+                assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), expected);
+            }
+        }
+
+        #[test]
+        fn small_reads_match_one_encode() {
+            let expected = machine().encode(TEXT);
+
+            for chunk in [1, 2, 5, 7] {
+                let mut reader = EncoderReader::new(TEXT.as_bytes(), machine());
+                let mut buf = vec![0; chunk];
+                let mut out = Vec::new();
+                loop {
+                    let bytes = reader.read(&mut buf).unwrap();
+                    if bytes == 0 {
+                        break;
+                    }
+                    out.extend_from_slice(&buf[..bytes]);
+                }
+                assert_eq!(String::from_utf8(out).unwrap(), expected);
+            }
+        }
+
+        #[test]
+        fn case_kept_round_trip_is_byte_identical() {
+            let original = "Hello World!\nGrüße aus Berlin, 1941.\r\n\tMixed CaSe x".as_bytes();
+
+            let encoded = read_all(EncoderReader::new(original, machine()).with_case_kept());
+            assert_ne!(encoded, original);
+
+            let decoded = read_all(EncoderReader::new(&encoded[..], machine()).with_case_kept());
+            assert_eq!(decoded, original);
+        }
+    }
+}
+
+pub mod fitness {
+    use crate::machine::{Error, MAX_WIRES};
+
+    pub const ENGLISH_PERCENT: [f64; MAX_WIRES] = [
+        7.856, 1.671, 2.306, 4.915, 12.038, 2.052, 2.249, 6.435, 6.399, 0.238, 1.014, 4.034, 2.423,
+        6.794, 7.887, 1.661, 0.060, 5.299, 5.946, 9.770, 3.041, 0.805, 2.656, 0.140, 2.160, 0.051,
+    ];
+    pub const FITNESS_NAMES: [&str; 6] = [
+        "freq-delta",
+        "ioc",
+        "chi-squared",
+        "bigram",
+        "trigram",
+        "quadgram",
+    ];
+
+    // Scores a candidate decrypt, higher meaning more like the language. Anything that isn't a
+    // letter is skipped, so punctuation and spacing make no difference
+    pub trait Fitness {
+        fn name(&self) -> &str;
+        fn score(&self, text: &[u8]) -> f64;
+    }
+
+    pub fn letter_counts(text: &[u8]) -> [u32; MAX_WIRES] {
+        let mut counts = [0; MAX_WIRES];
+
+        for byte in letters(text) {
+            counts[byte] += 1;
+        }
+
+        counts
+    }
+
+    // The L1 distance in percentage points between the letter frequencies and English
+    pub fn freq_delta(counts: &[u32; MAX_WIRES]) -> f64 {
+        freq_delta_from(counts, &ENGLISH_PERCENT)
+    }
+
+    pub fn freq_delta_from(counts: &[u32; MAX_WIRES], percent: &[f64; MAX_WIRES]) -> f64 {
+        let total: u32 = counts.iter().sum();
+        if total == 0 {
+            return 0.0;
+        }
+
+        counts
+            .iter()
+            .zip(percent)
+            .map(|(&count, expected)| (expected - 100.0 * count as f64 / total as f64).abs())
+            .sum()
+    }
+
+    fn letters(text: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        text.iter()
+            .filter(|b| b.is_ascii_alphabetic())
+            .map(|b| (b.to_ascii_uppercase() - b'A') as usize)
+    }
+
+    // Negated so that a smaller delta scores higher
+    pub struct FreqDelta {
+        percent: [f64; MAX_WIRES],
+    }
+
+    impl FreqDelta {
+        pub fn new(percent: [f64; MAX_WIRES]) -> FreqDelta {
+            FreqDelta { percent }
+        }
+    }
+
+    impl Default for FreqDelta {
+        fn default() -> FreqDelta {
+            FreqDelta::new(ENGLISH_PERCENT)
+        }
+    }
+
+    impl Fitness for FreqDelta {
+        fn name(&self) -> &str {
+            "freq-delta"
+        }
+
+        fn score(&self, text: &[u8]) -> f64 {
+            -freq_delta_from(&letter_counts(text), &self.percent)
+        }
+    }
+
+    // The chance two letters drawn from the text match, about 0.066 for English or German and
+    // 0.038 for random letters. It ignores which letters are common, so it still rewards a
+    // right rotor setting when the plugboard has yet to be found
+    pub struct IndexOfCoincidence;
+
+    impl Fitness for IndexOfCoincidence {
+        fn name(&self) -> &str {
+            "ioc"
+        }
+
+        fn score(&self, text: &[u8]) -> f64 {
+            let counts = letter_counts(text);
+            let total: u32 = counts.iter().sum();
+            if total < 2 {
+                return 0.0;
+            }
+
+            let pairs: f64 = counts.iter().map(|&n| n as f64 * (n as f64 - 1.0)).sum();
+            pairs / (total as f64 * (total as f64 - 1.0))
+        }
+    }
+
+    // Negated chi-squared statistic of the letter counts against a language's frequencies.
+    // Letters the language never uses are left out rather than dividing by zero
+    pub struct ChiSquared {
+        percent: [f64; MAX_WIRES],
+    }
+
+    impl ChiSquared {
+        pub fn new(percent: [f64; MAX_WIRES]) -> ChiSquared {
+            ChiSquared { percent }
+        }
+    }
+
+    impl Default for ChiSquared {
+        fn default() -> ChiSquared {
+            ChiSquared::new(ENGLISH_PERCENT)
+        }
+    }
+
+    impl Fitness for ChiSquared {
+        fn name(&self) -> &str {
+            "chi-squared"
+        }
+
+        fn score(&self, text: &[u8]) -> f64 {
+            let counts = letter_counts(text);
+            let total: u32 = counts.iter().sum();
+
+            -counts
+                .iter()
+                .zip(self.percent)
+                .filter(|&(_, percent)| percent > 0.0)
+                .map(|(&count, percent)| {
+                    let expected = percent * total as f64 / 100.0;
+                    (count as f64 - expected).powi(2) / expected
+                })
+                .sum::<f64>()
+        }
+    }
+
+    // Log-likelihood of the text's letter n-grams, averaged so texts of any length compare.
+    // N-grams never seen in training score as if they had turned up a hundredth of a time
+    #[derive(Debug, Clone)]
+    pub struct Ngrams {
+        name: &'static str,
+        n: usize,
+        counts: Vec<u64>,
+        log_probs: Vec<f64>,
+    }
+
+    impl Ngrams {
+        pub fn train(n: usize, corpus: &str) -> Result<Ngrams, Error> {
+            let mut counts = vec![0; ngram_table_size(n)?];
+
+            for index in ngram_indexes(n, corpus.as_bytes()) {
+                counts[index] += 1;
+            }
+
+            Ngrams::from_counts(n, counts)
+        }
+
+        fn from_counts(n: usize, counts: Vec<u64>) -> Result<Ngrams, Error> {
+            let name = ngram_name(n)?;
+            let total: u64 = counts.iter().sum();
+            if total == 0 {
+                return Err(Error::InvalidLanguageModel(format!(
+                    "the corpus has no {}s",
+                    name
+                )));
+            }
+
+            let floor = (0.01 / total as f64).log10();
+            let log_probs = counts
+                .iter()
+                .map(|&count| match count {
+                    0 => floor,
+                    count => (count as f64 / total as f64).log10(),
+                })
+                .collect();
+
+            Ok(Ngrams {
+                name,
+                n,
+                counts,
+                log_probs,
+            })
+        }
+
+        pub fn n(&self) -> usize {
+            self.n
+        }
+    }
+
+    fn ngram_name(n: usize) -> Result<&'static str, Error> {
+        match n {
+            2 => Ok("bigram"),
+            3 => Ok("trigram"),
+            4 => Ok("quadgram"),
+            _ => Err(Error::InvalidLanguageModel(format!(
+                "n-grams of {} letters are not supported, only 2 to 4",
+                n
+            ))),
+        }
+    }
+
+    fn ngram_table_size(n: usize) -> Result<usize, Error> {
+        ngram_name(n)?;
+        Ok(MAX_WIRES.pow(n as u32))
+    }
+
+    // Bigram, trigram and quadgram tables for one language. Saved models list each n-gram seen
+    // in training with its count, one per line, such as "TION 1234"
+    #[derive(Debug, Clone)]
+    pub struct LanguageModel {
+        bigrams: Ngrams,
+        trigrams: Ngrams,
+        quadgrams: Ngrams,
+    }
+
+    impl LanguageModel {
+        pub fn train(corpus: &str) -> Result<LanguageModel, Error> {
+            Ok(LanguageModel {
+                bigrams: Ngrams::train(2, corpus)?,
+                trigrams: Ngrams::train(3, corpus)?,
+                quadgrams: Ngrams::train(4, corpus)?,
+            })
+        }
+
+        pub fn english() -> LanguageModel {
+            LanguageModel::train(include_str!("../in/tomsawyer.txt"))
+                .expect("the English corpus must have n-grams")
+        }
+
+        // Trained on German written the way Enigma operators spelled it: upper case, AE, OE,
+        // UE and SS in place of umlauts and sharp s, numbers spelled out and X between words
+        pub fn german() -> LanguageModel {
+            LanguageModel::train(include_str!("../in/deutsch.txt"))
+                .expect("the German corpus must have n-grams")
+        }
+
+        pub fn by_name(name: &str) -> Result<LanguageModel, Error> {
+            match name.to_ascii_lowercase().as_str() {
+                "english" => Ok(LanguageModel::english()),
+                "german" => Ok(LanguageModel::german()),
+                _ => Err(Error::InvalidLanguageModel(format!(
+                    "unknown language '{}', expected english or german",
+                    name
+                ))),
+            }
+        }
+
+        pub fn from_model(text: &str) -> Result<LanguageModel, Error> {
+            let mut tables = [
+                vec![0; ngram_table_size(2)?],
+                vec![0; ngram_table_size(3)?],
+                vec![0; ngram_table_size(4)?],
+            ];
+
+            for line in text.lines().map(|line| line.trim()) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let bad_line = || Error::InvalidLanguageModel(format!("bad line '{}'", line));
+                let (ngram, count) = line.split_once(' ').ok_or_else(bad_line)?;
+                let count: u64 = count.trim().parse().map_err(|_| bad_line())?;
+                if ngram.len() < 2
+                    || ngram.len() > 4
+                    || !ngram.bytes().all(|b| b.is_ascii_uppercase())
+                {
+                    return Err(bad_line());
+                }
+
+                let index = ngram_indexes(ngram.len(), ngram.as_bytes())
+                    .next()
+                    .ok_or_else(bad_line)?;
+                tables[ngram.len() - 2][index] += count;
+            }
+
+            let [bigrams, trigrams, quadgrams] = tables;
+            Ok(LanguageModel {
+                bigrams: Ngrams::from_counts(2, bigrams)?,
+                trigrams: Ngrams::from_counts(3, trigrams)?,
+                quadgrams: Ngrams::from_counts(4, quadgrams)?,
+            })
+        }
+
+        pub fn to_model(&self) -> String {
+            let mut model = String::from("# enigma n-gram model\n");
+
+            for ngrams in [&self.bigrams, &self.trigrams, &self.quadgrams] {
+                for (index, &count) in ngrams.counts.iter().enumerate() {
+                    if count > 0 {
+                        model += &format!("{} {}\n", ngram_letters(ngrams.n, index), count);
+                    }
+                }
+            }
+
+            model
+        }
+
+        // Single letter frequencies, read off the first letter of every bigram
+        pub fn letter_percent(&self) -> [f64; MAX_WIRES] {
+            let mut counts = [0; MAX_WIRES];
+            for (index, &count) in self.bigrams.counts.iter().enumerate() {
+                counts[index / MAX_WIRES] += count;
+            }
+
+            let total: u64 = counts.iter().sum();
+            counts.map(|count| 100.0 * count as f64 / total as f64)
+        }
+
+        pub fn into_ngrams(self, n: usize) -> Result<Ngrams, Error> {
+            ngram_name(n)?;
+
+            Ok(match n {
+                2 => self.bigrams,
+                3 => self.trigrams,
+                _ => self.quadgrams,
+            })
+        }
+    }
+
+    fn ngram_letters(n: usize, index: usize) -> String {
+        (0..n)
+            .rev()
+            .map(|place| (b'A' + (index / MAX_WIRES.pow(place as u32) % MAX_WIRES) as u8) as char)
+            .collect()
+    }
+
+    impl Fitness for Ngrams {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn score(&self, text: &[u8]) -> f64 {
+            let (sum, count) = ngram_indexes(self.n, text).fold((0.0, 0), |(sum, count), index| {
+                (sum + self.log_probs[index], count + 1)
+            });
+
+            match count {
+                0 => f64::MIN,
+                count => sum / count as f64,
+            }
+        }
+    }
+
+    // Each run of n letters as a base-26 number, skipping anything that isn't a letter
+    fn ngram_indexes(n: usize, text: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let modulus = MAX_WIRES.pow(n as u32);
+        let mut index = 0;
+        let mut seen = 0;
+
+        letters(text).filter_map(move |letter| {
+            index = (index * MAX_WIRES + letter) % modulus;
+            seen += 1;
+            (seen >= n).then_some(index)
+        })
+    }
+
+    // Picks a scorer by name. Letter frequency and n-gram scorers follow the given language
+    // model, or English if there is none
+    pub fn fitness_by_name(
+        name: &str,
+        language: Option<LanguageModel>,
+    ) -> Result<Box<dyn Fitness>, Error> {
+        let percent = language
+            .as_ref()
+            .map_or(ENGLISH_PERCENT, LanguageModel::letter_percent);
+        let ngrams = |n| -> Result<Box<dyn Fitness>, Error> {
+            let language = language.unwrap_or_else(LanguageModel::english);
+            Ok(Box::new(language.into_ngrams(n)?))
+        };
+
+        match name {
+            "freq-delta" => Ok(Box::new(FreqDelta::new(percent))),
+            "ioc" => Ok(Box::new(IndexOfCoincidence)),
+            "chi-squared" => Ok(Box::new(ChiSquared::new(percent))),
+            "bigram" => ngrams(2),
+            "trigram" => ngrams(3),
+            "quadgram" => ngrams(4),
+            _ => Err(Error::UnknownFitness(name.to_string())),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const CORPUS: &str = "The quick brown fox jumps over the lazy dog, then the dog sleeps.";
+
+        #[test]
+        fn language_models_round_trip() {
+            let trained = LanguageModel::train(CORPUS).unwrap();
+            let saved = trained.to_model();
+            assert!(saved.lines().any(|line| line == "THE 4"));
+
+            let loaded = LanguageModel::from_model(&saved).unwrap();
+            assert_eq!(loaded.to_model(), saved);
+            assert_eq!(loaded.letter_percent(), trained.letter_percent());
+            for n in 2..=4 {
+                let before = trained.clone().into_ngrams(n).unwrap();
+                let after = loaded.clone().into_ngrams(n).unwrap();
+                assert_eq!(after.score(b"THEDOGJUMPS"), before.score(b"THEDOGJUMPS"));
+            }
+        }
+
+        #[test]
+        fn language_models_skip_comments_and_blank_lines() {
+            let model = "# comment\n\nTH 2\n  HE 1  \nTHE 1\nTHEN 1\n";
+            assert!(LanguageModel::from_model(model).is_ok());
+        }
+
+        #[test]
+        fn malformed_language_models_are_rejected() {
+            for line in [
+                "TH", "TH x", "th 2", "TH -1", "T 2", "THENS 2", "T1 2", "TH 2 3",
+            ] {
+                let model = format!("TH 2\nTHE 1\nTHEN 1\n{}\n", line);
+                assert!(
+                    matches!(
+                        LanguageModel::from_model(&model),
+                        Err(Error::InvalidLanguageModel(_))
+                    ),
+                    "{}",
+                    line
+                );
+            }
+
+            // Every table needs something in it
+            assert!(matches!(
+                LanguageModel::from_model("TH 2\nTHE 1\n"),
+                Err(Error::InvalidLanguageModel(_))
+            ));
+        }
+    }
+}
+
+pub mod analysis {
+    use crate::fitness::{Fitness, IndexOfCoincidence};
+    use crate::machine::*;
+    use crate::stream::encode_bytes;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+
+    // The highest scoring states offered so far, best first
+    pub struct Leaderboard {
+        capacity: usize,
+        entries: Vec<(f64, State)>,
+    }
+
+    impl Leaderboard {
+        pub fn new(capacity: usize) -> Leaderboard {
+            Leaderboard {
+                capacity,
+                entries: Vec::with_capacity(capacity + 1),
+            }
+        }
+
+        pub fn offer(&mut self, score: f64, state: State) -> bool {
+            if self.entries.len() == self.capacity
+                && self.entries.last().is_none_or(|(worst, _)| score <= *worst)
+            {
+                return false;
+            }
+
+            let index = self.entries.partition_point(|(s, _)| *s >= score);
+            self.entries.insert(index, (score, state));
+            self.entries.truncate(self.capacity);
+            true
+        }
+
+        pub fn best(&self) -> Option<&(f64, State)> {
+            self.entries.first()
+        }
+
+        pub fn entries(&self) -> &[(f64, State)] {
+            &self.entries
+        }
+
+        pub fn into_entries(self) -> Vec<(f64, State)> {
+            self.entries
+        }
+    }
+
+    // Decrypts the ciphertext from the state's starting position and scores the result
+    pub fn score_state(state: &State, ciphertext: &[u8], fitness: &dyn Fitness) -> f64 {
+        let mut machine = state.clone();
+        machine.reset();
+
+        let mut text = ciphertext.to_vec();
+        encode_bytes(&mut machine, &mut text);
+        fitness.score(&text)
+    }
+
+    // Seeded hill-climbing of a plugboard onto each candidate, keeping to max_pairs cables
+    pub struct PlugClimb {
+        pub max_pairs: usize,
+        pub restarts: usize,
+        pub seed: u64,
+    }
+
+    pub fn rank_states(
+        states: impl Iterator<Item = State>,
+        total: usize,
+        buffer: &[u8],
+        fitness: &dyn Fitness,
+        capacity: usize,
+    ) -> Leaderboard {
+        let mut best = Leaderboard::new(capacity);
+
+        debug!("Running through {} states for first pass", total);
+
+        let mut output = buffer.to_vec();
+
+        for (count, mut state) in states.enumerate() {
+            debug!("Trying: {}", state.show());
+
+            output.copy_from_slice(buffer);
+            encode_bytes(&mut state, &mut output);
+
+            let score = fitness.score(&output);
+
+            if best.offer(score, state) {
+                debug!("New candidate state found with score {}", score);
+            }
+
+            if count % 10 == 0 {
+                if let Some((best_score, best_state)) = best.best() {
+                    info!(
+                        "{:>10} Best state by {} ({}) so far: {}",
+                        count,
+                        fitness.name(),
+                        best_score,
+                        best_state.show()
+                    );
+                }
+            }
+
+            if output.len() < 60 {
+                trace!("  OUTPUT: [{}]", String::from_utf8_lossy(&output));
+            } else {
+                trace!(
+                    "  OUTPUT SAMPLE: [{}]",
+                    String::from_utf8_lossy(&output[..60])
+                );
+            }
+        }
+
+        best
+    }
+
+    pub fn optimise_candidate_rings(
+        candidates: Vec<(f64, State)>,
+        ciphertext: &[u8],
+        fitness: &dyn Fitness,
+    ) -> Vec<(f64, State)> {
+        let mut optimised = Leaderboard::new(candidates.len());
+
+        for (score, state) in candidates {
+            let (ringed_score, ringed) = optimise_rings(&state, ciphertext, fitness);
+
+            info!(
+                "Ring optimisation took {} from {} to {}",
+                ringed.show(),
+                score,
+                ringed_score
+            );
+            optimised.offer(ringed_score, ringed);
+        }
+
+        optimised.into_entries()
+    }
+
+    pub fn climb_plugboards(
+        candidates: Vec<(f64, State)>,
+        ciphertext: &[u8],
+        fitness: &dyn Fitness,
+        climb: &PlugClimb,
+    ) -> Result<Vec<(f64, State)>, Error> {
+        let mut rng = ChaCha20Rng::seed_from_u64(climb.seed);
+        let mut climbed = Leaderboard::new(candidates.len());
+
+        for (score, state) in candidates {
+            let (plugged_score, plugged) = climb_plugboard(
+                &state,
+                ciphertext,
+                fitness,
+                climb.max_pairs,
+                climb.restarts,
+                &mut rng,
+            )?;
+
+            info!(
+                "Plugboard climb took {} from {} to {}",
+                plugged.show(),
+                score,
+                plugged_score
+            );
+            climbed.offer(plugged_score, plugged);
+        }
+
+        Ok(climbed.into_entries())
+    }
+
+    // The whole ciphertext-only attack: rank every state by index of coincidence, which
+    // survives a missing plugboard, move the rings of the best few, climb a plugboard onto each
+    // by n-grams and then settle the rings again now the plugs are in. With no climb the rings
+    // and plugs are taken as known, leaving only the ranking and a final score by n-grams
+    pub fn crack(
+        states: impl Iterator<Item = State>,
+        total: usize,
+        ciphertext: &[u8],
+        ngrams: &dyn Fitness,
+        candidates: usize,
+        climb: Option<&PlugClimb>,
+    ) -> Result<Vec<(f64, State)>, Error> {
+        let ioc = IndexOfCoincidence;
+        let ranked = rank_states(states, total, ciphertext, &ioc, candidates).into_entries();
+
+        match climb {
+            None => Ok(rank_states(
+                ranked.into_iter().map(|(_, mut state)| {
+                    state.reset();
+                    state
+                }),
+                candidates,
+                ciphertext,
+                ngrams,
+                candidates,
+            )
+            .into_entries()),
+            Some(climb) => {
+                let ringed = optimise_candidate_rings(ranked, ciphertext, &ioc);
+                let plugged = climb_plugboards(ringed, ciphertext, ngrams, climb)?;
+                Ok(optimise_candidate_rings(plugged, ciphertext, ngrams))
+            }
+        }
+    }
+
+    // Tries every ring setting for the middle and right rotors, turning each window with its
+    // ring so the wiring cores start where the candidate had them. Only the turnover points
+    // move, which is all a search over window positions with the rings at A can't find
+    pub fn optimise_rings(state: &State, ciphertext: &[u8], fitness: &dyn Fitness) -> (f64, State) {
+        let rings = state.rings().map(|r| r as u8 - b'A');
+        let settings = state.setting().map(|s| s as u8 - b'A');
+        let turn = |letter: u8, by: u8| (b'A' + (letter + by) % MAX_WIRES as u8) as char;
+
+        let mut best = (score_state(state, ciphertext, fitness), state.clone());
+        for middle in 0..MAX_WIRES as u8 {
+            for right in 0..MAX_WIRES as u8 {
+                let candidate = state
+                    .clone()
+                    .with_rings_and_settings(
+                        [
+                            turn(rings[0], 0),
+                            turn(rings[1], middle),
+                            turn(rings[2], right),
+                        ],
+                        [
+                            turn(settings[0], 0),
+                            turn(settings[1], middle),
+                            turn(settings[2], right),
+                        ],
+                    )
+                    .expect("turned rings and settings must be letters A-Z");
+
+                let score = score_state(&candidate, ciphertext, fitness);
+                if score > best.0 {
+                    best = (score, candidate);
+                }
+            }
+        }
+
+        debug!("Ring optimisation reached {} with {}", best.0, best.1);
+        best
+    }
+
+    // Recovers plug pairs for a state whose rotors are already right, in the style of
+    // Weierud and Sullivan: try every pair of letters, adding, removing or trading cables, and
+    // keep any change that raises the score until a full pass finds nothing better. The first
+    // climb starts from the state's own plugs and each restart from a few random ones
+    pub fn climb_plugboard<R: Rng + ?Sized>(
+        state: &State,
+        ciphertext: &[u8],
+        fitness: &dyn Fitness,
+        max_pairs: usize,
+        restarts: usize,
+        rng: &mut R,
+    ) -> Result<(f64, State), Error> {
+        if max_pairs > MAX_PLUGS {
+            return Err(Error::TooManyPlugs(max_pairs));
+        }
+
+        let score = |wiring: &[usize; MAX_WIRES]| -> Result<f64, Error> {
+            let machine = state.clone().with_plugboard(plugboard(wiring)?);
+            Ok(score_state(&machine, ciphertext, fitness))
+        };
+
+        let mut best = (f64::MIN, [0; MAX_WIRES]);
+        for restart in 0..=restarts {
+            let start = match restart {
+                0 => wiring_of(&state.plugboard()),
+                _ => random_wiring(rng, max_pairs / 2),
+            };
+
+            let mut wiring = start;
+            let mut current = score(&wiring)?;
+            loop {
+                let mut improved = false;
+
+                for i in 0..MAX_WIRES {
+                    for j in i + 1..MAX_WIRES {
+                        for candidate in plug_moves(&wiring, i, j, max_pairs) {
+                            let candidate_score = score(&candidate)?;
+                            if candidate_score > current {
+                                current = candidate_score;
+                                wiring = candidate;
+                                improved = true;
+                            }
+                        }
+                    }
+                }
+
+                if !improved {
+                    break;
+                }
+            }
+
+            debug!(
+                "Plugboard climb {} reached {} with {}",
+                restart,
+                current,
+                plugboard(&wiring)?
+            );
+            if current > best.0 {
+                best = (current, wiring);
+            }
+        }
+
+        Ok((best.0, state.clone().with_plugboard(plugboard(&best.1)?)))
+    }
+
+    // The plugboards one move away that involve letters i and j
+    fn plug_moves(
+        wiring: &[usize; MAX_WIRES],
+        i: usize,
+        j: usize,
+        max_pairs: usize,
+    ) -> Vec<[usize; MAX_WIRES]> {
+        let pairs = (0..MAX_WIRES).filter(|&w| w < wiring[w]).count();
+        let (k, l) = (wiring[i], wiring[j]);
+        let mut moves = Vec::new();
+        let mut rewire = |unplug: &[usize], plug: &[(usize, usize)]| {
+            let mut next = *wiring;
+            for &w in unplug {
+                next[w] = w;
+            }
+            for &(a, b) in plug {
+                next[a] = b;
+                next[b] = a;
+            }
+            moves.push(next);
+        };
+
+        if k == j {
+            rewire(&[i, j], &[]);
+        } else if k == i && l == j {
+            if pairs < max_pairs {
+                rewire(&[], &[(i, j)]);
+            }
+        } else if l == j {
+            rewire(&[k], &[(i, j)]);
+            rewire(&[i], &[(k, j)]);
+        } else if k == i {
+            rewire(&[l], &[(i, j)]);
+            rewire(&[j], &[(i, l)]);
+        } else {
+            rewire(&[], &[(i, j), (k, l)]);
+            rewire(&[], &[(i, l), (j, k)]);
+        }
+
+        moves
+    }
+
+    fn wiring_of(plugs: &Plugboard) -> [usize; MAX_WIRES] {
+        let mut wiring: [usize; MAX_WIRES] = std::array::from_fn(|w| w);
+        for (a, b) in plugs.pairs() {
+            let (a, b) = ((a as u8 - b'A') as usize, (b as u8 - b'A') as usize);
+            wiring[a] = b;
+            wiring[b] = a;
+        }
+
+        wiring
+    }
+
+    fn random_wiring<R: Rng + ?Sized>(rng: &mut R, pairs: usize) -> [usize; MAX_WIRES] {
+        let mut letters: Vec<usize> = (0..MAX_WIRES).collect();
+        letters.shuffle(rng);
+
+        let mut wiring = wiring_of(&NO_PLUGS);
+        for pair in letters.chunks(2).take(pairs) {
+            wiring[pair[0]] = pair[1];
+            wiring[pair[1]] = pair[0];
+        }
+
+        wiring
+    }
+
+    fn plugboard(wiring: &[usize; MAX_WIRES]) -> Result<Plugboard, Error> {
+        let pairs: Vec<(char, char)> = (0..MAX_WIRES)
+            .filter(|&w| w < wiring[w])
+            .map(|w| (ALPHABET[w], ALPHABET[wiring[w]]))
+            .collect();
+
+        Plugboard::new(&pairs)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::factory::StateSet;
+        use crate::fitness::fitness_by_name;
+
+        const KEY: &str = "B II-V-III 07-13-22 KDQ";
+        const PLUGS: &str = "AV BS CG DL FU HZ IN KM OW RX";
+
+        // The first 300 letters of the sample chapter, encrypted with the given key
+        fn ciphertext(key: &str) -> Vec<u8> {
+            let plaintext: String = include_str!("../in/smalltom.txt")
+                .chars()
+                .filter(|c| c.is_ascii_alphabetic())
+                .take(300)
+                .collect();
+            let mut machine: State = key.parse().unwrap();
+            machine.encode(&plaintext).into_bytes()
+        }
+
+        #[test]
+        fn plug_climb_recovers_a_ten_pair_board() {
+            let quadgrams = fitness_by_name("quadgram", None).unwrap();
+            let unplugged: State = KEY.parse().unwrap();
+            let mut rng = ChaCha20Rng::seed_from_u64(1);
+
+            let (_, climbed) = climb_plugboard(
+                &unplugged,
+                &ciphertext(&format!("{} {}", KEY, PLUGS)),
+                quadgrams.as_ref(),
+                10,
+                3,
+                &mut rng,
+            )
+            .unwrap();
+            assert_eq!(climbed.plugboard(), PLUGS.parse().unwrap());
+        }
+
+        #[test]
+        fn ring_optimisation_recovers_middle_and_right_rings() {
+            let quadgrams = fitness_by_name("quadgram", None).unwrap();
+            // The middle rotor reaches its notch early on, so its ring shows in when the left
+            // rotor steps
+            let key = format!("B II-V-III 01-13-22 KYQ {}", PLUGS);
+
+            // The same wiring cores with every ring at 01, as the IoC ranking would find them
+            let core: State = format!("B II-V-III 01-01-01 KMV {}", PLUGS)
+                .parse()
+                .unwrap();
+            let (_, ringed) = optimise_rings(&core, &ciphertext(&key), quadgrams.as_ref());
+            assert_eq!(ringed.to_string(), key);
+        }
+
+        // Every order of rotors I, II and III behind reflector B, small enough to crack in a test
+        fn reduced_states(rings: [char; 3], plugs: Plugboard) -> (StateSet, usize) {
+            let states = StateSet::with_components(
+                all_rotors()[..3].to_vec(),
+                vec![all_reflectors()[1].clone()],
+                rings,
+                plugs,
+            )
+            .unwrap();
+            let total = states.total();
+            (states, total)
+        }
+
+        // The first 600 letters of the novel, about as few as the IoC ranking can work with
+        fn long_ciphertext(key: &str) -> Vec<u8> {
+            let plaintext: String = include_str!("../in/tomsawyer.txt")
+                .chars()
+                .filter(|c| c.is_ascii_alphabetic())
+                .take(600)
+                .collect();
+            key.parse::<State>()
+                .unwrap()
+                .encode(&plaintext)
+                .into_bytes()
+        }
+
+        #[test]
+        fn crack_recovers_rotors_rings_and_plugs() {
+            let quadgrams = fitness_by_name("quadgram", None).unwrap();
+            let key = "B II-III-I 01-05-17 PLU AV BS CG DL FU";
+            let ciphertext = long_ciphertext(key);
+            let (states, total) = reduced_states(['A', 'A', 'A'], NO_PLUGS);
+            let climb = PlugClimb {
+                max_pairs: 10,
+                restarts: 0,
+                seed: 1,
+            };
+
+            let cracked = crack(
+                states,
+                total,
+                &ciphertext,
+                quadgrams.as_ref(),
+                3,
+                Some(&climb),
+            )
+            .unwrap();
+            assert_eq!(cracked[0].1.to_string(), key);
+        }
+
+        #[test]
+        fn crack_keeps_known_rings_and_plugs() {
+            let quadgrams = fitness_by_name("quadgram", None).unwrap();
+            let key = "B III-I-II 04-22-09 ZEN AV BS CG DL FU HZ IN KM OW RX";
+            let ciphertext = long_ciphertext(key);
+            let known: State = key.parse().unwrap();
+            let (states, total) = reduced_states(known.rings(), known.plugboard());
+
+            let cracked = crack(states, total, &ciphertext, quadgrams.as_ref(), 3, None).unwrap();
+            assert_eq!(cracked[0].1.to_string(), key);
+        }
+
+        #[test]
+        fn plug_climb_keeps_to_max_pairs() {
+            let quadgrams = fitness_by_name("quadgram", None).unwrap();
+            let unplugged: State = KEY.parse().unwrap();
+            let mut rng = ChaCha20Rng::seed_from_u64(1);
+
+            for max_pairs in [0, 1, 3, 6] {
+                let (_, climbed) = climb_plugboard(
+                    &unplugged,
+                    &ciphertext(&format!("{} {}", KEY, PLUGS)),
+                    quadgrams.as_ref(),
+                    max_pairs,
+                    2,
+                    &mut rng,
+                )
+                .unwrap();
+                assert!(climbed.plugboard().len() <= max_pairs, "{}", max_pairs);
+            }
+
+            assert_eq!(
+                climb_plugboard(
+                    &unplugged,
+                    &ciphertext(&format!("{} {}", KEY, PLUGS)),
+                    quadgrams.as_ref(),
+                    MAX_PLUGS + 1,
+                    0,
+                    &mut rng
+                )
+                .err(),
+                Some(Error::TooManyPlugs(MAX_PLUGS + 1))
+            );
         }
     }
 }
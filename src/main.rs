@@ -8,18 +8,39 @@ extern crate log;
 
 #[macro_use]
 extern crate clap;
-use clap::{App, AppSettings};
+use clap::{App, AppSettings, ArgMatches};
 
 use std::fs;
+use std::fs::File;
 use std::path::Path;
+use std::process;
 
 use std::io;
 use std::io::prelude::*;
 
 use std::collections::HashMap;
+use std::error::Error;
 
-use enigma::machine::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+use enigma::analysis::*;
 use enigma::factory::*;
+use enigma::fitness::*;
+use enigma::machine::*;
+use enigma::stream::*;
+
+type CommandResult<T = ()> = Result<T, Box<dyn Error>>;
+
+const UKW_D_RESTARTS: usize = 8;
+
+// Stages that refine the best few states a search turns up, first the rings and then the
+// plugboard
+struct Refine {
+    candidates: usize,
+    rings: bool,
+    plugs: Option<PlugClimb>,
+}
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
@@ -32,182 +53,878 @@ fn main() {
         0 => "OFF",
         1 => "INFO",
         2 => "DEBUG",
-        3 | _ => "TRACE",
+        _ => "TRACE",
     };
 
     env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
 
+    if let Err(e) = run(&matches) {
+        eprintln!("enigma: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> CommandResult {
     match matches.subcommand() {
-        ("rand-io", Some(_)) => {
-            command_rand_io();
+        ("rand-io", Some(sub_m)) => {
+            let (machine, _) = key_or_random(sub_m)?;
+
+            command_rand_io(machine)
         }
         ("rand-dir", Some(sub_m)) => {
+            let (machine, seed) = key_or_random(sub_m)?;
+
             command_rand_dir(
                 sub_m.value_of("source").unwrap(),
                 sub_m.value_of("destination").unwrap(),
-            );
+                sub_m.value_of("suffix").unwrap_or(""),
+                machine,
+                seed,
+            )
         }
-        ("stats-io", Some(_)) => {
-            stats_io();
+        ("decode-dir", Some(sub_m)) => command_decode_dir(
+            sub_m.value_of("source").unwrap(),
+            sub_m.value_of("destination").unwrap(),
+            parse_key(sub_m.value_of("key"))?,
+        ),
+        ("stats-io", Some(sub_m)) => stats_io(parse_key(sub_m.value_of("key"))?),
+        ("search-io", Some(sub_m)) if sub_m.is_present("ukw_d") => {
+            let (model, components) = model_components(sub_m)?;
+            let key = fit_model(
+                State::from_key(sub_m.value_of("key").unwrap(), &components)?,
+                model.as_ref(),
+            )?;
+
+            command_search_ukw_d_io(
+                key,
+                load_fitness(sub_m)?.as_ref(),
+                parse_seed(sub_m.value_of("seed"))?,
+                json_output(sub_m)?,
+            )
         }
-        ("search-io", Some(_)) => {
-            command_search_io();
+        ("search-io", Some(sub_m)) => {
+            let (model, components) = model_components(sub_m)?;
+            let key = match sub_m.value_of("key") {
+                Some(key) => Some(fit_model(
+                    State::from_key(key, &components)?,
+                    model.as_ref(),
+                )?),
+                None => None,
+            };
+
+            command_search_io(
+                &components,
+                model.as_ref(),
+                key,
+                load_fitness(sub_m)?.as_ref(),
+                &refine_stages(sub_m)?,
+                json_output(sub_m)?,
+            )
+        }
+        ("search-m4-io", Some(sub_m)) => command_search_m4_io(
+            parse_key(sub_m.value_of("key"))?,
+            load_fitness(sub_m)?.as_ref(),
+            &refine_stages(sub_m)?,
+            json_output(sub_m)?,
+        ),
+        ("crack", Some(sub_m)) => {
+            let (model, components) = model_components(sub_m)?;
+            let key = match sub_m.value_of("key") {
+                Some(key) => Some(fit_model(
+                    State::from_key(key, &components)?,
+                    model.as_ref(),
+                )?),
+                None => None,
+            };
+
+            command_crack_io(
+                &components,
+                model.as_ref(),
+                key,
+                fitness_by_name("quadgram", load_language(sub_m)?)?.as_ref(),
+                parse_count(sub_m, "candidates", 10)?.max(1),
+                &plug_climb(sub_m)?,
+                parse_count(sub_m, "top", 3)?.max(1),
+            )
+        }
+        ("encode-io", Some(sub_m)) if sub_m.is_present("key") => {
+            let (model, components) = model_components(sub_m)?;
+
+            command_encode_key_io(sub_m.value_of("key").unwrap(), &components, model.as_ref())
+        }
+        ("encode-m4-io", Some(sub_m)) if sub_m.is_present("key") => {
+            command_encode_m4_key_io(sub_m.value_of("key").unwrap())
         }
         ("encode-io", Some(sub_m)) => {
+            let (model, components) = model_components(sub_m)?;
+            let rings: Vec<&str> = sub_m
+                .values_of("rings")
+                .map(|rings| rings.collect())
+                .unwrap_or_else(|| vec!["A", "A", "A"]);
+
             command_encode_io(
-                sub_m.value_of("left_rotor").unwrap(),
-                sub_m.value_of("center_rotor").unwrap(),
-                sub_m.value_of("right_rotor").unwrap(),
-                sub_m.value_of("left_rotor_start").unwrap(),
-                sub_m.value_of("center_rotor_start").unwrap(),
-                sub_m.value_of("right_rotor_start").unwrap(),
+                [
+                    sub_m.value_of("left_rotor").unwrap(),
+                    sub_m.value_of("center_rotor").unwrap(),
+                    sub_m.value_of("right_rotor").unwrap(),
+                ],
+                [rings[0], rings[1], rings[2]],
+                [
+                    sub_m.value_of("left_rotor_start").unwrap(),
+                    sub_m.value_of("center_rotor_start").unwrap(),
+                    sub_m.value_of("right_rotor_start").unwrap(),
+                ],
                 sub_m.value_of("reflector").unwrap(),
-            );
+                sub_m.value_of("plugs").unwrap_or(""),
+                &components,
+                model.as_ref(),
+            )
         }
+        ("encode-m4-io", Some(sub_m)) => {
+            let rings: Vec<&str> = sub_m
+                .values_of("rings")
+                .map(|rings| rings.collect())
+                .unwrap_or_else(|| vec!["A", "A", "A", "A"]);
+
+            command_encode_m4_io(
+                [
+                    sub_m.value_of("greek_rotor").unwrap(),
+                    sub_m.value_of("left_rotor").unwrap(),
+                    sub_m.value_of("center_rotor").unwrap(),
+                    sub_m.value_of("right_rotor").unwrap(),
+                ],
+                [rings[0], rings[1], rings[2], rings[3]],
+                [
+                    sub_m.value_of("greek_rotor_start").unwrap(),
+                    sub_m.value_of("left_rotor_start").unwrap(),
+                    sub_m.value_of("center_rotor_start").unwrap(),
+                    sub_m.value_of("right_rotor_start").unwrap(),
+                ],
+                sub_m.value_of("reflector").unwrap(),
+                sub_m.value_of("plugs").unwrap_or(""),
+            )
+        }
+        ("encode-g-io", Some(sub_m)) => command_encode_g_io(
+            sub_m.value_of("key").unwrap(),
+            sub_m.value_of("model").unwrap_or(G_MODELS[0]),
+        ),
+        ("train-model", Some(sub_m)) => command_train_model(
+            sub_m.value_of("source").unwrap(),
+            sub_m.value_of("destination").unwrap(),
+        ),
         _ => unreachable!("Unknown subcommand"),
     }
 }
 
 fn command_encode_io(
-    left_rotor: &str,
-    center_rotor: &str,
-    right_rotor: &str,
-    left_rotor_start: &str,
-    center_rotor_start: &str,
-    right_rotor_start: &str,
+    rotors: [&str; 3],
+    rings: [&str; 3],
+    starts: [&str; 3],
     reflector: &str,
-) {
+    plugs: &str,
+    components: &Components,
+    model: Option<&Model>,
+) -> CommandResult {
     info!("Running ENCODE-IO subcommand");
     debug!(
-        " Config = Rotors: {} {} {}  Start: {} {} {} Reflector: {}",
-        left_rotor,
-        center_rotor,
-        right_rotor,
-        left_rotor_start,
-        center_rotor_start,
-        right_rotor_start,
-        reflector
+        " Config = Rotors: {} {} {}  Rings: {} {} {}  Start: {} {} {} Reflector: {} Plugs: {}",
+        rotors[0],
+        rotors[1],
+        rotors[2],
+        rings[0],
+        rings[1],
+        rings[2],
+        starts[0],
+        starts[1],
+        starts[2],
+        reflector,
+        plugs
     );
 
-    encode_io(State::new(
+    let machine = State::new(
         (
-            rotor_by_name(left_rotor),
-            rotor_by_name(center_rotor),
-            rotor_by_name(right_rotor),
+            components.rotor_by_name(rotors[0])?,
+            components.rotor_by_name(rotors[1])?,
+            components.rotor_by_name(rotors[2])?,
         ),
         [
-            left_rotor_start.chars().next().unwrap(),
-            center_rotor_start.chars().next().unwrap(),
-            right_rotor_start.chars().next().unwrap(),
+            parse_ring(rings[0])?,
+            parse_ring(rings[1])?,
+            parse_ring(rings[2])?,
+        ],
+        [
+            parse_start(starts[0])?,
+            parse_start(starts[1])?,
+            parse_start(starts[2])?,
         ],
-        NO_PLUGS,
-        reflector_by_name(reflector),
-    ));
+        parse_plugs(plugs)?,
+        components.reflector_by_name(reflector)?,
+    )?;
+
+    encode_io(fit_model(machine, model)?)
 }
 
-fn command_rand_io() {
-    info!("Running RAND-IO subcommand");
+fn command_encode_key_io(
+    key: &str,
+    components: &Components,
+    model: Option<&Model>,
+) -> CommandResult {
+    info!("Running ENCODE-IO subcommand");
+    debug!(" Config = Key: {}", key);
 
-    encode_io(State::new_random());
+    encode_io(fit_model(State::from_key(key, components)?, model)?)
 }
 
-fn encode_io(machine: State) {
-    info!("Encoding with {}", machine.show());
+fn command_encode_m4_key_io(key: &str) -> CommandResult {
+    info!("Running ENCODE-M4-IO subcommand");
+    debug!(" Config = Key: {}", key);
 
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let text = line.expect("Error reading from STDIN: {}");
-        trace!("Read from STDIN: {}", text);
-        let output = machine.encode(&text);
-        println!("{}", output);
+    let machine: State = key.parse()?;
+    if machine.greek_rotor().is_none() {
+        return Err(format!("encode-m4-io needs a four rotor key: {}", key).into());
     }
+
+    encode_io(machine)
 }
 
-fn command_search_io() {
-    info!("Running SEARCH-IO subcommand");
+fn command_encode_g_io(key: &str, model: &str) -> CommandResult {
+    info!("Running ENCODE-G-IO subcommand");
+    debug!(" Config = Key: {}  Model: {}", key, model);
+
+    let mut machine = EnigmaG::from_key(key, &g_components(model)?)?;
+    info!("Encoding with key {}", machine);
+
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .map_err(|e| format!("unable to read STDIN: {}", e))?;
 
-    let states = StateSet::new();
-    let mut best_state_by_freq = State::new_random();
-    let mut best_freq = 2600.0;
+    let mut stdout = io::stdout();
+    stdout
+        .write_all(machine.encode(&text).as_bytes())
+        .and_then(|_| stdout.flush())
+        .map_err(|e| format!("unable to write to STDOUT: {}", e))?;
 
-    let mut count = 0;
+    Ok(())
+}
 
+fn command_encode_m4_io(
+    rotors: [&str; 4],
+    rings: [&str; 4],
+    starts: [&str; 4],
+    reflector: &str,
+    plugs: &str,
+) -> CommandResult {
+    info!("Running ENCODE-M4-IO subcommand");
     debug!(
-        "Running through {} states for first pass",
-        StateSet::MAX_STATES
+        " Config = Rotors: {} {} {} {}  Rings: {} {} {} {}  Start: {} {} {} {} Reflector: {} Plugs: {}",
+        rotors[0],
+        rotors[1],
+        rotors[2],
+        rotors[3],
+        rings[0],
+        rings[1],
+        rings[2],
+        rings[3],
+        starts[0],
+        starts[1],
+        starts[2],
+        starts[3],
+        reflector,
+        plugs
     );
 
-    let mut stdin = io::stdin();
-    let mut buffer = String::new();
-    let bytes = stdin
-        .read_to_string(&mut buffer)
-        .expect("Problem reading from STDIN");
+    encode_io(State::new_m4(
+        (
+            greek_rotor_by_name(rotors[0])?,
+            rotor_by_name(rotors[1])?,
+            rotor_by_name(rotors[2])?,
+            rotor_by_name(rotors[3])?,
+        ),
+        [
+            parse_ring(rings[0])?,
+            parse_ring(rings[1])?,
+            parse_ring(rings[2])?,
+            parse_ring(rings[3])?,
+        ],
+        [
+            parse_start(starts[0])?,
+            parse_start(starts[1])?,
+            parse_start(starts[2])?,
+            parse_start(starts[3])?,
+        ],
+        parse_plugs(plugs)?,
+        thin_reflector_by_name(reflector)?,
+    )?)
+}
 
-    debug!("Read {} bytes from STDIN", bytes);
+// Without --model no model rules apply, and any built-in or --components part may be used
+fn model_components(sub_m: &ArgMatches) -> CommandResult<(Option<Model>, Components)> {
+    match sub_m.value_of("model") {
+        Some(name) => {
+            let model = model_by_name(name)?;
+            let components = model.components().clone();
+
+            info!("Using the Enigma {} model", model.name());
+            Ok((Some(model), components))
+        }
+        None => Ok((None, load_components(sub_m.value_of("components"))?)),
+    }
+}
 
-    for state in states {
-        debug!("Trying: {}", state.show());
+fn fit_model(machine: State, model: Option<&Model>) -> CommandResult<State> {
+    match model {
+        Some(model) => Ok(model.configure(machine)?),
+        None => Ok(machine),
+    }
+}
+
+fn load_components(path: Option<&str>) -> CommandResult<Components> {
+    match path {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("unable to read components {}: {}", path, e))?;
+            let components = Components::from_toml(&text)?;
+
+            info!(
+                "Loaded {} rotors, {} reflectors and {} entry wheels from {}",
+                components.rotors().len(),
+                components.reflectors().len(),
+                components.entry_wheels().len(),
+                path
+            );
+            Ok(components)
+        }
+        None => Ok(Components::builtin()),
+    }
+}
 
-        let output = state.encode(&buffer);
+fn parse_key(key: Option<&str>) -> CommandResult<Option<State>> {
+    match key {
+        Some(key) => Ok(Some(key.parse()?)),
+        None => Ok(None),
+    }
+}
 
-        let (_, _, freq_delta) = gen_stats(&output);
+fn key_or_random(sub_m: &ArgMatches) -> CommandResult<(State, Option<u64>)> {
+    let (model, components) = model_components(sub_m)?;
 
-        if freq_delta < best_freq {
-            best_freq = freq_delta;
-            best_state_by_freq = state;
+    match sub_m.value_of("key") {
+        Some(key) => Ok((
+            fit_model(State::from_key(key, &components)?, model.as_ref())?,
+            None,
+        )),
+        None => {
+            let seed = parse_seed(sub_m.value_of("seed"))?;
+            let plug_pairs = parse_plug_pairs(sub_m.value_of("plug_pairs"))?;
+            let machine = match model {
+                Some(model) => model.seeded_state(seed, plug_pairs)?,
+                None => State::new_seeded(seed, plug_pairs)?,
+            };
 
-            debug!("New best state found {} < {}", freq_delta, best_freq);
+            Ok((machine, Some(seed)))
         }
+    }
+}
+
+fn parse_plugs(plugs: &str) -> CommandResult<Plugboard> {
+    plugs
+        .parse()
+        .map_err(|e| format!("invalid plugboard '{}': {}", plugs, e).into())
+}
+
+fn parse_start(start: &str) -> CommandResult<char> {
+    let mut letters = start.chars();
+
+    match (letters.next(), letters.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Ok(c.to_ascii_uppercase()),
+        _ => Err(format!("expected a single letter A-Z: '{}'", start).into()),
+    }
+}
+
+fn parse_plug_pairs(count: Option<&str>) -> CommandResult<usize> {
+    match count {
+        Some(count) => match count.parse::<usize>() {
+            Ok(n) if n <= MAX_PLUGS => Ok(n),
+            _ => Err(format!(
+                "plug pairs must be a number from 0 to {}: {}",
+                MAX_PLUGS, count
+            )
+            .into()),
+        },
+        None => Ok(DEFAULT_PLUG_PAIRS),
+    }
+}
+
+fn parse_seed(seed: Option<&str>) -> CommandResult<u64> {
+    let seed = match seed {
+        Some(seed) => seed
+            .parse::<u64>()
+            .map_err(|_| format!("seed must be an unsigned 64 bit number: {}", seed))?,
+        None => rand::random(),
+    };
+
+    info!("Using random seed {}", seed);
+    Ok(seed)
+}
+
+fn command_rand_io(machine: State) -> CommandResult {
+    info!("Running RAND-IO subcommand");
+
+    encode_io(machine)
+}
+
+fn encode_io(machine: State) -> CommandResult {
+    info!("Encoding with key {}", machine);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut writer = EncoderWriter::new(stdout.lock(), machine);
+
+    let bytes = io::copy(&mut stdin.lock(), &mut writer)
+        .map_err(|e| format!("unable to encode STDIN: {}", e))?;
+    writer
+        .flush()
+        .map_err(|e| format!("unable to write to STDOUT: {}", e))?;
+
+    debug!("Encoded {} bytes from STDIN", bytes);
+    Ok(())
+}
+
+fn command_search_io(
+    components: &Components,
+    model: Option<&Model>,
+    key: Option<State>,
+    fitness: &dyn Fitness,
+    refine: &Refine,
+    json: bool,
+) -> CommandResult {
+    info!("Running SEARCH-IO subcommand");
+
+    let (rings, plugs) = key_rings_and_plugs(key);
+    let entry_wheel = model.map(|m| m.entry_wheel().clone()).unwrap_or_default();
+    let states = StateSet::with_components(
+        components.rotors().to_vec(),
+        components.reflectors().to_vec(),
+        rings,
+        plugs,
+    )?
+    .with_entry_wheel(entry_wheel)
+    .with_settable_reflector(model.is_some_and(|m| m.has_settable_reflector()));
+    let total = states.total();
+
+    search_io(states, total, fitness, refine, json)
+}
+
+fn command_search_m4_io(
+    key: Option<State>,
+    fitness: &dyn Fitness,
+    refine: &Refine,
+    json: bool,
+) -> CommandResult {
+    info!("Running SEARCH-M4-IO subcommand");
+
+    let (rings, plugs) = key_rings_and_plugs(key);
+    let states = M4StateSet::with_plugs(rings, plugs)?;
+    let total = states.total();
+
+    search_io(states, total, fitness, refine, json)
+}
 
-        if count % 10 == 0 {
+fn key_rings_and_plugs(key: Option<State>) -> ([char; 3], Plugboard) {
+    match key {
+        Some(key) => {
             info!(
-                "{:>10} Best state by frequency ({}) so far: {}",
-                count,
-                best_freq,
-                best_state_by_freq.show()
+                "Searching with rings {:?} and plugs {} from the key",
+                key.rings(),
+                key.plugboard()
             );
+            (key.rings(), key.plugboard())
         }
+        None => (['A', 'A', 'A'], NO_PLUGS),
+    }
+}
 
-        if output.len() < 60 {
-            trace!("  OUTPUT: [{}]", output);
-        } else {
-            trace!("  OUTPUT SAMPLE: [{}]", (&output[..60]));
-        }
+fn search_io(
+    states: impl Iterator<Item = State>,
+    total: usize,
+    fitness: &dyn Fitness,
+    refine: &Refine,
+    json: bool,
+) -> CommandResult {
+    let buffer = read_stdin()?;
+    let capacity = match refine.rings || refine.plugs.is_some() {
+        true => refine.candidates,
+        false => 1,
+    };
 
-        count += 1;
+    let mut candidates = rank_states(states, total, &buffer, fitness, capacity).into_entries();
+    if refine.rings {
+        candidates = optimise_candidate_rings(candidates, &buffer, fitness);
+    }
+    if let Some(climb) = &refine.plugs {
+        candidates = climb_plugboards(candidates, &buffer, fitness, climb)?;
     }
 
-    println!(
-        "Best guess is below using state: {}",
-        best_state_by_freq.show()
-    );
+    let (best_score, best_state) = candidates
+        .into_iter()
+        .next()
+        .ok_or("no candidate states were searched")?;
+    print_best_guess(best_state, fitness, best_score, buffer, json)
+}
+
+fn read_stdin() -> CommandResult<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let bytes = io::stdin()
+        .read_to_end(&mut buffer)
+        .map_err(|e| format!("unable to read from STDIN: {}", e))?;
+
+    debug!("Read {} bytes from STDIN", bytes);
+    Ok(buffer)
+}
+
+// Cracks STDIN with every rotor order, reflector and start position of the components,
+// taking the rings and plugs from a key if there is one
+fn command_crack_io(
+    components: &Components,
+    model: Option<&Model>,
+    key: Option<State>,
+    ngrams: &dyn Fitness,
+    candidates: usize,
+    climb: &PlugClimb,
+    top: usize,
+) -> CommandResult {
+    info!("Running CRACK subcommand");
+
+    let known = key.is_some();
+    let (rings, plugs) = key_rings_and_plugs(key);
+    let entry_wheel = model.map(|m| m.entry_wheel().clone()).unwrap_or_default();
+    let states = StateSet::with_components(
+        components.rotors().to_vec(),
+        components.reflectors().to_vec(),
+        rings,
+        plugs,
+    )?
+    .with_entry_wheel(entry_wheel)
+    .with_settable_reflector(model.is_some_and(|m| m.has_settable_reflector()));
+    let total = states.total();
+    let buffer = read_stdin()?;
+
+    if letter_counts(&buffer).iter().all(|&count| count == 0) {
+        return Err("no letters to crack on STDIN".into());
+    }
+
+    let cracked = crack(
+        states,
+        total,
+        &buffer,
+        ngrams,
+        candidates,
+        (!known).then_some(climb),
+    )?;
+
+    match known {
+        true => println!("Top {} keys by {}:", top.min(cracked.len()), ngrams.name()),
+        false => println!(
+            "Top {} keys by {} (seed {}):",
+            top.min(cracked.len()),
+            ngrams.name(),
+            climb.seed
+        ),
+    }
+    for (rank, (score, mut state)) in cracked.into_iter().take(top).enumerate() {
+        let mut output = buffer.clone();
+        state.reset();
+        let key = state.to_string();
+        encode_bytes(&mut state, &mut output);
+
+        println!("------------------------------------------------------");
+        println!("{}. {} (score {})", rank + 1, key, score);
+        println!("------------------------------------------------------");
+        println!("{}", String::from_utf8_lossy(&output));
+    }
     println!("------------------------------------------------------");
-    println!("{}", best_state_by_freq.encode(&buffer));
+    Ok(())
+}
+
+fn refine_stages(sub_m: &ArgMatches) -> CommandResult<Refine> {
+    let plugs = match sub_m.is_present("climb_plugs") {
+        true => Some(plug_climb(sub_m)?),
+        false => None,
+    };
+
+    Ok(Refine {
+        candidates: parse_count(sub_m, "candidates", 10)?.max(1),
+        rings: sub_m.is_present("climb_rings"),
+        plugs,
+    })
+}
+
+fn plug_climb(sub_m: &ArgMatches) -> CommandResult<PlugClimb> {
+    Ok(PlugClimb {
+        max_pairs: parse_count(sub_m, "max_pairs", DEFAULT_PLUG_PAIRS)?,
+        restarts: parse_count(sub_m, "restarts", 3)?,
+        seed: parse_seed(sub_m.value_of("seed"))?,
+    })
+}
+
+fn parse_count(sub_m: &ArgMatches, name: &str, default: usize) -> CommandResult<usize> {
+    match sub_m.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a whole number: {}", name, value).into()),
+        None => Ok(default),
+    }
+}
+
+fn print_best_guess(
+    mut best: State,
+    fitness: &dyn Fitness,
+    score: f64,
+    mut buffer: Vec<u8>,
+    json: bool,
+) -> CommandResult {
+    best.reset();
+    info!("Best {} score is {}", fitness.name(), score);
+
+    if json {
+        let mut machine = best.clone();
+        encode_bytes(&mut machine, &mut buffer);
+
+        return print_search_result(&best, fitness.name(), score, &buffer);
+    }
+
+    encode_bytes(&mut best, &mut buffer);
+
+    println!("Best guess is below using key: {}", best);
+    println!("------------------------------------------------------");
+    println!("{}", String::from_utf8_lossy(&buffer));
     println!("------------------------------------------------------");
+    Ok(())
+}
+
+// Keeps the rotors, rings, settings and plugs of the key and hill-climbs the UKW-D wiring,
+// swapping partners between two pairs while that improves the fitness score and starting
+// again from a fresh random wiring a few times
+fn command_search_ukw_d_io(
+    key: State,
+    fitness: &dyn Fitness,
+    seed: u64,
+    json: bool,
+) -> CommandResult {
+    info!("Running SEARCH-IO subcommand for a UKW-D wiring");
+
+    let buffer = read_stdin()?;
+
+    let score = |reflector: &Reflector| {
+        let mut machine = key.clone().with_reflector(reflector.clone());
+        let mut output = buffer.clone();
+        encode_bytes(&mut machine, &mut output);
+
+        fitness.score(&output)
+    };
+
+    let mut rng = ChaCha20Rng::seed_from_u64(seed);
+    let mut best = None;
+    let mut best_score = f64::MIN;
+
+    for restart in 0..UKW_D_RESTARTS {
+        let mut reflector = random_ukw_d(&mut rng);
+        let mut current = score(&reflector);
+
+        loop {
+            let climb = reflector
+                .ukw_d_neighbours()
+                .into_iter()
+                .map(|r| (score(&r), r))
+                .max_by(|a, b| a.0.total_cmp(&b.0));
+
+            match climb {
+                Some((s, r)) if s > current => {
+                    current = s;
+                    reflector = r;
+                }
+                _ => break,
+            }
+        }
+
+        info!(
+            "Climb {} settled on {} with {} score {}",
+            restart,
+            reflector.name(),
+            fitness.name(),
+            current
+        );
+        if current > best_score {
+            best_score = current;
+            best = Some(reflector);
+        }
+    }
+
+    let best = best.ok_or("no UKW-D wirings were searched")?;
+    print_best_guess(key.with_reflector(best), fitness, best_score, buffer, json)
+}
+
+fn json_output(sub_m: &ArgMatches) -> CommandResult<bool> {
+    let json = sub_m.is_present("json");
+
+    if json && !cfg!(feature = "serde") {
+        return Err("JSON output needs enigma built with the serde feature".into());
+    }
+
+    Ok(json)
+}
+
+fn load_fitness(sub_m: &ArgMatches) -> CommandResult<Box<dyn Fitness>> {
+    let name = sub_m.value_of("fitness").unwrap_or("freq-delta");
+    let fitness = fitness_by_name(name, load_language(sub_m)?)?;
+
+    info!("Scoring candidates by {}", fitness.name());
+    Ok(fitness)
+}
+
+fn load_language(sub_m: &ArgMatches) -> CommandResult<Option<LanguageModel>> {
+    let language = match (
+        sub_m.value_of("corpus"),
+        sub_m.value_of("language_model"),
+        sub_m.value_of("language"),
+    ) {
+        (Some(path), _, _) => Some(LanguageModel::train(
+            &fs::read_to_string(path)
+                .map_err(|e| format!("unable to read corpus {}: {}", path, e))?,
+        )?),
+        (_, Some(path), _) => Some(LanguageModel::from_model(
+            &fs::read_to_string(path)
+                .map_err(|e| format!("unable to read language model {}: {}", path, e))?,
+        )?),
+        (_, _, Some(name)) => Some(LanguageModel::by_name(name)?),
+        _ => None,
+    };
+
+    Ok(language)
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct SearchResult<'a> {
+    state: &'a State,
+    fitness: &'a str,
+    score: f64,
+    freq_delta: f64,
+    plaintext: String,
 }
 
-fn command_rand_dir(source: &str, dest: &str) {
+#[cfg(feature = "serde")]
+fn print_search_result(
+    state: &State,
+    fitness: &str,
+    score: f64,
+    plaintext: &[u8],
+) -> CommandResult {
+    let result = SearchResult {
+        state,
+        fitness,
+        score,
+        freq_delta: freq_delta(&letter_counts(plaintext)),
+        plaintext: String::from_utf8_lossy(plaintext).into_owned(),
+    };
+
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_search_result(
+    _state: &State,
+    _fitness: &str,
+    _score: f64,
+    _plaintext: &[u8],
+) -> CommandResult {
+    Err("JSON output needs enigma built with the serde feature".into())
+}
+
+fn command_train_model(source: &str, dest: &str) -> CommandResult {
+    info!(
+        "Running TRAIN-MODEL subcommand for source: {} and dest: {}",
+        source, dest
+    );
+
+    let source = Path::new(source);
+    let mut paths = Vec::new();
+    if source.is_dir() {
+        for entry in fs::read_dir(source)
+            .map_err(|e| format!("unable to read directory {}: {}", source.display(), e))?
+        {
+            let path = entry
+                .map_err(|e| format!("unable to read directory {}: {}", source.display(), e))?
+                .path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+    } else {
+        paths.push(source.to_path_buf());
+    }
+
+    let mut corpus = String::new();
+    for path in &paths {
+        let bytes =
+            fs::read(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        debug!("Training on {} ({} bytes)", path.display(), bytes.len());
+        corpus += &String::from_utf8_lossy(&bytes);
+    }
+
+    let model = LanguageModel::train(&corpus)?.to_model();
+    fs::write(dest, &model)
+        .map_err(|e| format!("unable to write language model {}: {}", dest, e))?;
+
+    info!(
+        "Trained on {} files into {} n-grams",
+        paths.len(),
+        model.lines().count() - 1
+    );
+    Ok(())
+}
+
+fn command_rand_dir(
+    source: &str,
+    dest: &str,
+    suffix: &str,
+    machine: State,
+    seed: Option<u64>,
+) -> CommandResult {
     info!(
         "Running RAND-DIR subcommand for source: {} and dest: {}",
         source, dest
     );
 
-    let machine = State::new_random();
-    info!("Encoding with {}", machine.show());
+    info!("Encoding with key {}", machine);
 
     let source_path = Path::new(source);
     let dest_path = Path::new(dest);
 
     debug!("Ensuring dest directory and parents exist");
-    fs::create_dir_all(dest_path).expect("Unable to create parent directory");
+    create_dir(dest_path)?;
 
-    for entry in fs::read_dir(source_path).expect("Unable to list source directory") {
-        let in_path = entry.expect("unable to get entry in source").path();
+    let mut files = Vec::new();
+    let entries = fs::read_dir(source_path)
+        .map_err(|e| format!("unable to list {}: {}", source_path.display(), e))?;
+
+    for entry in entries {
+        let in_path = entry
+            .map_err(|e| format!("unable to list {}: {}", source_path.display(), e))?
+            .path();
 
         if in_path.is_dir() {
             trace!("Skipping {} as directory", in_path.display());
         } else {
-            let out_path = dest_path.join(in_path.file_name().expect("Unable to find file name"));
+            let file_name = in_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("file name is not valid UTF-8: {}", in_path.display()))?
+                .to_string();
+            let out_path = dest_path.join(format!("{}{}", file_name, suffix));
 
             debug!(
                 "Processing file: {} to {}",
@@ -215,38 +932,175 @@ fn command_rand_dir(source: &str, dest: &str) {
                 out_path.display()
             );
 
-            let text =
-                fs::read_to_string(in_path).expect("Something went wrong reading the source file");
+            encode_file(machine.clone(), &in_path, &out_path)?;
+            files.push(file_name);
+        }
+    }
+
+    let manifest_path = dest_path.join(KEY_MANIFEST);
+    write_manifest(&manifest_path, &machine, seed, suffix, &files)?;
+    info!("Wrote key manifest to {}", manifest_path.display());
+    Ok(())
+}
+
+fn command_decode_dir(source: &str, dest: &str, key: Option<State>) -> CommandResult {
+    info!(
+        "Running DECODE-DIR subcommand for source: {} and dest: {}",
+        source, dest
+    );
+
+    let source_path = Path::new(source);
+    let dest_path = Path::new(dest);
 
-            let output = machine.encode(&text);
+    let (manifest_machine, suffix, files) = read_manifest(&source_path.join(KEY_MANIFEST))?;
+    let machine = key.unwrap_or(manifest_machine);
+    info!("Decoding with key {}", machine);
+
+    debug!("Ensuring dest directory and parents exist");
+    create_dir(dest_path)?;
 
-            fs::write(out_path, output).expect("Unable to write dest file");
+    for file_name in files {
+        let in_path = source_path.join(format!("{}{}", file_name, suffix));
+        let out_path = dest_path.join(&file_name);
+
+        debug!(
+            "Processing file: {} to {}",
+            in_path.display(),
+            out_path.display()
+        );
+
+        encode_file(machine.clone(), &in_path, &out_path)?;
+    }
+
+    Ok(())
+}
+
+fn create_dir(path: &Path) -> CommandResult {
+    fs::create_dir_all(path)
+        .map_err(|e| format!("unable to create {}: {}", path.display(), e).into())
+}
+
+fn encode_file(machine: State, in_path: &Path, out_path: &Path) -> CommandResult {
+    let source =
+        File::open(in_path).map_err(|e| format!("unable to open {}: {}", in_path.display(), e))?;
+    let mut dest = File::create(out_path)
+        .map_err(|e| format!("unable to create {}: {}", out_path.display(), e))?;
+
+    let mut encoder = EncoderReader::new(source, machine).with_case_kept();
+    io::copy(&mut encoder, &mut dest).map_err(|e| {
+        format!(
+            "unable to encode {} to {}: {}",
+            in_path.display(),
+            out_path.display(),
+            e
+        )
+    })?;
+
+    Ok(())
+}
+
+const KEY_MANIFEST: &str = "enigma.key";
+
+fn write_manifest(
+    path: &Path,
+    machine: &State,
+    seed: Option<u64>,
+    suffix: &str,
+    files: &[String],
+) -> CommandResult {
+    let mut manifest = String::from("# enigma rand-dir key manifest\n");
+    manifest += &format!("key = {}\n", machine);
+    if let Some(seed) = seed {
+        manifest += &format!("seed = {}\n", seed);
+    }
+    manifest += &format!("suffix = {}\n", suffix);
+
+    for file_name in files {
+        manifest += &format!("file = {}\n", file_name);
+    }
+
+    fs::write(path, manifest)
+        .map_err(|e| format!("unable to write key manifest {}: {}", path.display(), e).into())
+}
+
+fn read_manifest(path: &Path) -> CommandResult<(State, String, Vec<String>)> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read key manifest {}: {}", path.display(), e))?;
+
+    let mut values = HashMap::new();
+    let mut files = Vec::new();
+
+    for line in text.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("malformed key manifest line: {}", line))?;
+
+        match key.trim() {
+            "file" => files.push(value.trim().to_string()),
+            key => {
+                values.insert(key, value.trim());
+            }
         }
     }
+
+    let field = |name: &str| -> CommandResult<&str> {
+        values
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("key manifest is missing {}", name).into())
+    };
+    let machine = field("key")?
+        .parse()
+        .map_err(|e| format!("invalid key in key manifest: {}", e))?;
+
+    Ok((machine, field("suffix")?.to_string(), files))
 }
 
-fn stats_io() {
+fn stats_io(key: Option<State>) -> CommandResult {
     info!("Running STATS-IO subcommand");
 
-    let mut stdin = io::stdin();
-    let mut buffer = String::new();
+    let stdin = io::stdin();
+    let mut stdin: Box<dyn Read> = match key {
+        Some(machine) => {
+            info!("Decoding with key {}", machine);
+            Box::new(EncoderReader::new(stdin.lock(), machine))
+        }
+        None => Box::new(stdin.lock()),
+    };
+    let mut buffer = [0; 8192];
+    let mut bytes_read = 0;
+    let mut char_count = [0; MAX_WIRES];
+
+    loop {
+        let bytes = match stdin.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("unable to read from STDIN: {}", e).into()),
+        };
+
+        bytes_read += bytes;
+        for (total, count) in char_count.iter_mut().zip(letter_counts(&buffer[..bytes])) {
+            *total += count;
+        }
+    }
 
-    stdin
-        .read_to_string(&mut buffer)
-        .expect("Error reading from STDIN");
+    let num_chars: u32 = char_count.iter().sum();
+    let freq_delta = freq_delta(&char_count);
 
     println!("STDIN STATS");
-    println!("  Bytes read: {}", buffer.len());
-
-    let (char_count, num_chars, freq_delta) = gen_stats(&buffer);
+    println!("  Bytes read: {}", bytes_read);
 
     println!(
         "  Character Counts ({} unique ascii alpha present):",
         num_chars
     );
-    for c in ALPHABET {
-        let count = char_count.get(&c).ok_or(0).expect("Character error");
-        let percent = 100.0 * *count as f64 / num_chars as f64;
+    for (c, count) in ALPHABET.iter().zip(char_count) {
+        let percent = 100.0 * count as f64 / num_chars as f64;
 
         println!(
             "    {} {:12} {:>7.3} {:-^4$}",
@@ -259,32 +1113,5 @@ fn stats_io() {
     }
 
     println!("  Character frequency delta: {}", freq_delta);
-}
-
-const NORMAL_PERCENT: [f64; 26] = [
-    7.856, 1.671, 2.306, 4.915, 12.038, 2.052, 2.249, 6.435, 6.399, 0.238, 1.014, 4.034, 2.423,
-    6.794, 7.887, 1.661, 0.060, 5.299, 5.946, 9.770, 3.041, 0.805, 2.656, 0.140, 2.160, 0.051,
-];
-
-fn gen_stats(buffer: &String) -> (HashMap<char, u32>, u32, f64) {
-    let mut char_count = HashMap::new();
-    let mut num_chars = 0;
-
-    for character in buffer.chars().filter(|c| c.is_ascii() && c.is_alphabetic()) {
-        let count = char_count
-            .entry(character.to_ascii_uppercase())
-            .or_insert(0);
-        *count += 1;
-        num_chars += 1;
-    }
-
-    let mut freq_delta: f64 = 0.0;
-
-    for (k, v) in &char_count {
-        let expected = NORMAL_PERCENT[((*k as u8) - b'A') as usize];
-        let actual = 100.0 * *v as f64 / num_chars as f64;
-        freq_delta += (expected - actual).abs();
-    }
-
-    (char_count, num_chars, freq_delta)
+    Ok(())
 }